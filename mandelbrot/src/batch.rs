@@ -0,0 +1,318 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+
+use crate::overlay::{self, Overlay};
+use crate::stats::RenderStats;
+use crate::viewport::{self, Viewport};
+use crate::{parse_complex, parse_pair, render, write_image};
+
+/*
+Batch rendering
+
+A job file lists one image per line:
+
+    OUTPUT PIXELS UPPERLEFT LOWERRIGHT [OPTIONS]
+
+for example
+
+    # nightly views
+    overview.png  1000x750 -1.20,0.35 -1,0.20
+    seahorse.png  800x600  -0.76,0.12 -0.74,0.10 limit=1000 rotate=30
+
+The options are 'limit=N' plus the viewport and overlay options the
+single-image command takes, written without the leading dashes (see
+'viewport::apply_option' and 'overlay::parse_option').
+
+Blank lines and lines starting with '#' are ignored.
+
+Every job is cut into bands and the bands of all jobs go onto a single queue.
+A fixed pool of worker threads drains that queue, so a batch of dozens of
+images never spawns more than 'threads' workers. Finished bands are sent back
+to the main thread, which writes each image as soon as its last band arrives.
+ */
+
+#[derive(Debug, PartialEq)]
+struct Job {
+    output: String,
+    bounds: (usize, usize),
+    viewport: Viewport,
+    limit: usize,
+    overlay: Option<Overlay>,
+}
+
+// A horizontal strip of one job's image, waiting for a worker.
+struct Band {
+    job: usize,
+    top: usize,
+    height: usize,
+}
+
+// A band after a worker has rendered it.
+struct RenderedBand {
+    job: usize,
+    top: usize,
+    pixels: Vec<u8>,
+    started: Instant,
+    finished: Instant,
+    stats: RenderStats,
+}
+
+// What the summary report shows for each job.
+struct JobReport {
+    output: String,
+    bounds: (usize, usize),
+    bands: usize,
+    wall: Duration,
+    cpu: Duration,
+    stats: RenderStats,
+    error: Option<String>,
+}
+
+fn print_usage(program: &str) {
+    eprintln!("Usage: {} batch JOBFILE [THREADS]", program);
+    eprintln!("Each line of JOBFILE is: OUTPUT PIXELS UPPERLEFT LOWERRIGHT [OPTIONS]");
+}
+
+// Entry point for 'mandelbrot batch ...'; 'args' is the full command line.
+pub fn run(args: &[String]) {
+    if args.len() < 3 || args.len() > 4 {
+        print_usage(&args[0]);
+        std::process::exit(1);
+    }
+
+    let threads = match args.get(3) {
+        None => 8,
+        Some(s) => match s.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("error parsing thread count '{}'", s);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let text = fs::read_to_string(&args[2]).expect("error reading job file");
+    let jobs = match parse_jobs(&text) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("{}: {}", args[2], e);
+            std::process::exit(1);
+        }
+    };
+
+    let started = Instant::now();
+    let reports = render_batch(&jobs, threads);
+    print_report(&reports, started.elapsed(), threads);
+
+    if reports.iter().any(|r| r.error.is_some()) {
+        std::process::exit(1);
+    }
+}
+
+// Parse one non-empty, non-comment line of a job file.
+fn parse_job(line: &str) -> Result<Job, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        return Err(format!("expected OUTPUT PIXELS UPPERLEFT LOWERRIGHT, got '{}'", line));
+    }
+
+    let bounds = parse_pair(fields[1], 'x')
+        .ok_or_else(|| format!("error parsing image dimensions '{}'", fields[1]))?;
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return Err(format!("image dimensions must be non-zero, got '{}'", fields[1]));
+    }
+    let upper_left = parse_complex(fields[2])
+        .ok_or_else(|| format!("error parsing upper left corner point '{}'", fields[2]))?;
+    let lower_right = parse_complex(fields[3])
+        .ok_or_else(|| format!("error parsing lower right corner point '{}'", fields[3]))?;
+
+    let mut job = Job {
+        output: fields[0].to_string(),
+        bounds,
+        viewport: Viewport::from_corners(bounds, upper_left, lower_right),
+        limit: 255,
+        overlay: None,
+    };
+    let center = job.viewport.center(bounds);
+
+    for option in &fields[4..] {
+        if let Some(value) = option.strip_prefix("limit=") {
+            job.limit = match value.parse() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(format!("error parsing iteration limit '{}'", value)),
+            };
+            continue;
+        }
+        if let Some(o) = overlay::parse_option(option)? {
+            job.overlay = Some(o);
+            continue;
+        }
+        job.viewport = viewport::apply_option(job.viewport, center, option)?
+            .ok_or_else(|| format!("unknown option '{}'", option))?;
+    }
+
+    Ok(job)
+}
+
+// Parse a whole job file, reporting errors with their line number.
+fn parse_jobs(text: &str) -> Result<Vec<Job>, String> {
+    let mut jobs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        jobs.push(parse_job(line).map_err(|e| format!("line {}: {}", number + 1, e))?);
+    }
+    Ok(jobs)
+}
+
+#[test]
+fn test_parse_jobs() {
+    use num::Complex;
+    let text = "# comment\n\
+                \n\
+                a.png 100x50 -1,1 1,-1\n\
+                b.png 10x10 -0.5,0.5 0.5,-0.5 limit=1000 flip-x overlay=grid\n";
+    let a = Viewport::from_corners((100, 50), Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let b = Viewport::from_corners((10, 10), Complex { re: -0.5, im: 0.5 }, Complex { re: 0.5, im: -0.5 });
+    assert_eq!(parse_jobs(text), Ok(vec![
+        Job { output: "a.png".to_string(), bounds: (100, 50), viewport: a, limit: 255, overlay: None },
+        Job { output: "b.png".to_string(), bounds: (10, 10),
+              viewport: b.transformed(Complex { re: 0.0, im: 0.0 }, [[-1.0, 0.0], [0.0, 1.0]]), limit: 1000,
+              overlay: Some(Overlay { grid: true, axes: false, labels: false, scale_bar: false }) },
+    ]));
+
+    assert!(parse_jobs("a.png 100x50 -1,1").unwrap_err().starts_with("line 1:"));
+    assert!(parse_jobs("\na.png 0x50 -1,1 1,-1").unwrap_err().starts_with("line 2:"));
+    assert!(parse_jobs("a.png 100x50 -1,1 1,-1 limit=0").is_err());
+    assert!(parse_jobs("a.png 100x50 -1,1 1,-1 colour=red").is_err());
+}
+
+// Render every job on a shared pool of 'threads' workers and write the images.
+fn render_batch(jobs: &[Job], threads: usize) -> Vec<JobReport> {
+    let mut buffers: Vec<Vec<u8>> = jobs.iter().map(|job| vec![0; job.bounds.0 * job.bounds.1]).collect();
+    let mut remaining = vec![0; jobs.len()];
+    let mut reports: Vec<JobReport> = jobs.iter().map(|job| JobReport {
+        output: job.output.clone(),
+        bounds: job.bounds,
+        bands: 0,
+        wall: Duration::ZERO,
+        cpu: Duration::ZERO,
+        stats: RenderStats::default(),
+        error: None,
+    }).collect();
+    let mut first_start: Vec<Option<Instant>> = vec![None; jobs.len()];
+
+    let (band_sender, band_receiver) = channel::unbounded::<Band>();
+    let (done_sender, done_receiver) = channel::unbounded::<RenderedBand>();
+
+    for (i, job) in jobs.iter().enumerate() {
+        let rows_per_band = job.bounds.1 / threads + 1;
+        let mut top = 0;
+        while top < job.bounds.1 {
+            let height = rows_per_band.min(job.bounds.1 - top);
+            band_sender.send(Band { job: i, top, height }).unwrap();
+            remaining[i] += 1;
+            top += height;
+        }
+        reports[i].bands = remaining[i];
+    }
+    drop(band_sender);
+
+    crossbeam::scope(|spawner| {
+        for _ in 0..threads {
+            let band_receiver = band_receiver.clone();
+            let done_sender = done_sender.clone();
+            spawner.spawn(move |_| {
+                for band in band_receiver {
+                    let job = &jobs[band.job];
+                    let width = job.bounds.0;
+                    let band_viewport = job.viewport.band(band.top);
+                    let mut pixels = vec![0; width * band.height];
+                    let started = Instant::now();
+                    let stats = render(&mut pixels, (width, band.height), &band_viewport, job.limit);
+                    let finished = Instant::now();
+                    done_sender.send(RenderedBand { job: band.job, top: band.top, pixels, started, finished, stats })
+                        .unwrap();
+                }
+            });
+        }
+        // Only the workers' clones should keep the channel open.
+        drop(done_sender);
+
+        for band in done_receiver {
+            let job = &jobs[band.job];
+            let start = band.top * job.bounds.0;
+            buffers[band.job][start..start + band.pixels.len()].copy_from_slice(&band.pixels);
+
+            let report = &mut reports[band.job];
+            report.cpu += band.finished - band.started;
+            report.stats.merge(&band.stats);
+            let first = *first_start[band.job].get_or_insert(band.started);
+            report.wall = report.wall.max(band.finished - first);
+
+            remaining[band.job] -= 1;
+            if remaining[band.job] == 0 {
+                if let Some(overlay) = &job.overlay {
+                    overlay.draw(&mut buffers[band.job], job.bounds, &job.viewport);
+                }
+                if let Err(e) = write_image(&job.output, &buffers[band.job], job.bounds) {
+                    report.error = Some(e.to_string());
+                }
+                // The image is on disk; don't hold on to its pixels.
+                buffers[band.job] = Vec::new();
+            }
+        }
+    }).unwrap();
+
+    reports
+}
+
+#[test]
+fn test_render_batch() {
+    use num::Complex;
+    let dir = std::env::temp_dir().join(format!("mandelbrot-batch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    let viewport = |bounds| Viewport::from_corners(bounds, Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let jobs = vec![
+        Job { output: output("a.png"), bounds: (40, 30), viewport: viewport((40, 30)), limit: 255,
+              overlay: overlay::parse_option("overlay").unwrap() },
+        Job { output: output("b.png"), bounds: (7, 3), viewport: viewport((7, 3)), limit: 50, overlay: None },
+        Job { output: output("missing/c.png"), bounds: (5, 5), viewport: viewport((5, 5)), limit: 50, overlay: None },
+    ];
+    let reports = render_batch(&jobs, 4);
+
+    assert_eq!(reports.len(), 3);
+    assert!(reports[0].error.is_none() && fs::metadata(&jobs[0].output).is_ok());
+    assert!(reports[1].error.is_none() && fs::metadata(&jobs[1].output).is_ok());
+    assert!(reports[2].error.is_some());
+    assert_eq!(reports[1].bands, 3);
+    assert_eq!(reports[1].stats.escaped + reports[1].stats.interior, 7 * 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn print_report(reports: &[JobReport], elapsed: Duration, threads: usize) {
+    println!("{:<30} {:>11} {:>6} {:>10} {:>10} {:>13} {:>9}  status",
+             "output", "size", "bands", "wall ms", "cpu ms", "iterations", "interior");
+    for report in reports {
+        let size = format!("{}x{}", report.bounds.0, report.bounds.1);
+        let status = match &report.error {
+            None => "ok".to_string(),
+            Some(e) => format!("error: {}", e),
+        };
+        println!("{:<30} {:>11} {:>6} {:>10.1} {:>10.1} {:>13} {:>9}  {}",
+                 report.output, size, report.bands,
+                 report.wall.as_secs_f64() * 1000.0, report.cpu.as_secs_f64() * 1000.0,
+                 report.stats.total_iterations, report.stats.interior, status);
+    }
+
+    let failed = reports.iter().filter(|r| r.error.is_some()).count();
+    println!("{} jobs ({} failed) in {:.1} ms on {} threads",
+             reports.len(), failed, elapsed.as_secs_f64() * 1000.0, threads);
+}
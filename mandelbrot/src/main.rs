@@ -1,13 +1,37 @@
 use std::str::FromStr;
 use num::Complex;
 use std::env;
+use std::time::Instant;
+
+mod batch;
+mod overlay;
+mod poi;
+mod stats;
+mod viewport;
+
+use overlay::Overlay;
+use stats::{BandStats, RenderStats};
+use viewport::Viewport;
 
 // Non concurrent
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 5 {
-        eprintln!("Usage: {} FILE PIXELS UPPERLEFT LOWERRIGHT", args[0]);
+    if args.get(1).map(String::as_str) == Some("batch") {
+        batch::run(&args);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("poi") {
+        poi::run(&args);
+        return;
+    }
+
+    if args.len() < 5 {
+        eprintln!("Usage: {} FILE PIXELS UPPERLEFT LOWERRIGHT [OPTIONS]", args[0]);
+        eprintln!("       {} batch JOBFILE [THREADS]", args[0]);
+        eprintln!("       {} poi UPPERLEFT LOWERRIGHT [SAMPLES] [LIMIT]", args[0]);
+        eprintln!("Options: --rotate=DEG --flip-x --flip-y --skew=K --affine=A,B,C,D");
+        eprintln!("         --overlay[=grid,axes,labels,scale] --stats[=text|json]");
         eprint!("Example: {} mandel.png 1000x750 -1.20,0.35, -1,0.20", args[0]);
         std::process::exit(1);
     }
@@ -16,40 +40,98 @@ fn main() {
     let upper_left = parse_complex(&args[3]).expect("error parsing upper left corner point");
     let lower_right = parse_complex(&args[4]).expect("error parsing lower right corner point");
 
+    let options = match parse_options(&args[5..], bounds, upper_left, lower_right) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let viewport = options.viewport;
+
     let mut pixels = vec![0; bounds.0 * bounds.1];
 
     let threads = 8;
+    let limit = 255;
     let rows_per_band = bounds.1 / threads + 1;
+    let started = Instant::now();
 
-    {
+    let band_stats = {
         let bands: Vec<&mut [u8]> = pixels.chunks_mut(rows_per_band * bounds.0).collect();
         crossbeam::scope(|spawner | {
+            let mut handles = Vec::new();
             for(i, band) in bands.into_iter().enumerate() {
                 let top = rows_per_band * i;
                 let height = band.len() / bounds.0;
                 let band_bounds = (bounds.0, height);
-                let band_upper_left = pixel_to_point(bounds, (0, top), upper_left, lower_right);
-                let band_lower_right = pixel_to_point(bounds, (bounds.0, top + height), upper_left, lower_right);
-                spawner.spawn(move |_| {
-                    render(band, band_bounds, band_upper_left, band_lower_right);
-                });
+                let band_viewport = viewport.band(top);
+                handles.push(spawner.spawn(move |_| {
+                    let started = Instant::now();
+                    let stats = render(band, band_bounds, &band_viewport, limit);
+                    BandStats { top, height, wall: started.elapsed(), stats }
+                }));
             }
-        }).unwrap();
+            handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        }).unwrap()
+    };
+
+    if let Some(format) = options.stats {
+        let report = stats::Report { bounds, limit, threads, wall: started.elapsed(), bands: band_stats };
+        report.print(format);
     }
 
+    if let Some(overlay) = options.overlay {
+        overlay.draw(&mut pixels, bounds, &viewport);
+    }
 
     write_image(&args[1], &pixels, bounds).expect("error writing PNG file");
 
 }
 
+// Everything given after LOWERRIGHT on the command line.
+struct Options {
+    viewport: Viewport,
+    overlay: Option<Overlay>,
+    stats: Option<stats::Format>,
+}
+
+// Viewport options are applied in the order given, always around the center of the image.
+fn parse_options(args: &[String],
+                 bounds: (usize, usize),
+                 upper_left: Complex<f64>,
+                 lower_right: Complex<f64>) -> Result<Options, String> {
+    let mut options = Options {
+        viewport: Viewport::from_corners(bounds, upper_left, lower_right),
+        overlay: None,
+        stats: None,
+    };
+    let center = options.viewport.center(bounds);
+
+    for arg in args {
+        let unknown = || format!("unknown option '{}'", arg);
+        let name = arg.strip_prefix("--").ok_or_else(unknown)?;
+        if let Some(overlay) = overlay::parse_option(name)? {
+            options.overlay = Some(overlay);
+            continue;
+        }
+        if let Some(format) = stats::parse_option(name)? {
+            options.stats = Some(format);
+            continue;
+        }
+        options.viewport = viewport::apply_option(options.viewport, center, name)?.ok_or_else(unknown)?;
+    }
+    Ok(options)
+}
 
 // An infinite loop - using Rust's dedicated syntax
+#[allow(dead_code)]
 fn square_loop(mut x:f64) {
     loop {
         x = x * x;
     }
 }
 
+#[allow(dead_code)]
 fn square_add_loop(c: f64){
     let mut x = 0.;
     loop {
@@ -57,6 +139,7 @@ fn square_add_loop(c: f64){
     }
 }
 
+#[allow(dead_code)]
 fn complex_square_add_loop(c: Complex<f64>) {
     let mut z = Complex { re: 0.0, im: 0.0 };
     loop {
@@ -117,10 +200,7 @@ fn test_parse_pair() {
 
 // Parse a pair of floating point coordinates and return them as a Complex<f64> value:
 fn parse_complex(s: &str) -> Option<Complex<f64>>{
-    match parse_pair(s, ','){
-        Some((re, im)) => Some(Complex {re, im}),
-        None => None
-    }
+    parse_pair(s, ',').map(|(re, im)| Complex {re, im})
 }
 
 #[test]
@@ -162,27 +242,62 @@ fn test_pixel_to_point(){
 Render a rectangle of the Mandelbrot set into a buffer of Pixels
 
 The 'bounds' argument gives the width and height of the buffer 'pixels'
-which holds one grayscale pixel per byte. The 'upper_left' and 'lower_right;
-arguments specify points on the complex plane corresponding to the upper-left
-and lower-right corners of the pixel buffer.
+which holds one grayscale pixel per byte. The 'viewport' maps each pixel of
+the buffer to the point on the complex plane it shows; see 'Viewport'.
+
+'limit' is the iteration limit passed to 'escape_time'. Escape counts are
+scaled so the darkest shade always means "escaped just before the limit",
+whatever the limit is.
+
+Returns counts of the work done, for '--stats'.
 
  */
 fn render(pixels: &mut [u8],
           bounds: (usize, usize),
-          upper_left: Complex<f64>,
-          lower_right: Complex<f64>){
+          viewport: &Viewport,
+          limit: usize) -> RenderStats {
 
     assert_eq!(pixels.len(), bounds.0 * bounds.1);
 
+    let mut stats = RenderStats::default();
     for row in 0..bounds.1 {
         for column in 0..bounds.0 {
-            let point = pixel_to_point(bounds, (column, row), upper_left, lower_right);
-            pixels[row * bounds.0 + column] = match escape_time(point, 255) {
+            let point = viewport.pixel_to_point((column as f64, row as f64));
+            let count = escape_time(point, limit);
+            stats.record(count, limit);
+            pixels[row * bounds.0 + column] = match count {
                 None => 0,
-                Some(count) => 255 - count as u8
+                Some(count) => shade(count, limit)
             };
         }
     }
+    stats
+}
+
+#[test]
+fn test_render() {
+    let bounds = (4, 2);
+    let viewport = Viewport::from_corners(bounds, Complex { re: -2.0, im: 1.0 }, Complex { re: 2.0, im: -1.0 });
+    let mut pixels = vec![7; 8];
+    let stats = render(&mut pixels, bounds, &viewport, 100);
+    // The pixels show -2+i, -1+i, i, 1+i and -2, -1, 0, 1; of those i, -2, -1 and 0 are in the set.
+    assert_eq!(pixels.iter().map(|&p| p == 0).collect::<Vec<_>>(),
+               vec![false, false, true, false, true, true, true, false]);
+    assert_eq!((stats.escaped, stats.interior), (4, 4));
+    assert!(stats.total_iterations > 4 * 100);
+}
+
+// Map an escape count below 'limit' to a grayscale value; with the classic limit of 255 this is '255 - count'.
+fn shade(count: usize, limit: usize) -> u8 {
+    (255 - count * 255 / limit) as u8
+}
+
+#[test]
+fn test_shade() {
+    assert_eq!(shade(0, 255), 255);
+    assert_eq!(shade(254, 255), 1);
+    assert_eq!(shade(10, 255), 245);
+    assert_eq!(shade(500, 1000), 128);
 }
 
 use image::ColorType;
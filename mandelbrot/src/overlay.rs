@@ -0,0 +1,364 @@
+use num::Complex;
+
+use crate::viewport::Viewport;
+
+/*
+Annotations drawn on top of a rendered image: grid lines at round complex
+coordinates, the real and imaginary axes, coordinate labels and a scale bar.
+
+The overlay is composited after 'render' and before 'write_image'. Everything
+is placed by taking points on the complex plane back to pixels with
+'Viewport::point_to_pixel', so grid lines stay at the right coordinates even
+when the view is rotated, flipped or skewed.
+
+Lines are drawn in whichever of black or white contrasts with the pixel
+underneath; labels are white on a black box.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overlay {
+    pub grid: bool,
+    pub axes: bool,
+    pub labels: bool,
+    pub scale_bar: bool,
+}
+
+const ALL: Overlay = Overlay { grid: true, axes: true, labels: true, scale_bar: true };
+
+/*
+Parse an 'overlay' option, written without any leading dashes. Plain
+'overlay' turns everything on; 'overlay=grid,labels' picks layers from
+'grid', 'axes', 'labels' and 'scale'. Returns Ok(None) for other options.
+ */
+pub fn parse_option(option: &str) -> Result<Option<Overlay>, String> {
+    let layers = match option.strip_prefix("overlay") {
+        Some("") => return Ok(Some(ALL)),
+        Some(rest) => match rest.strip_prefix('=') {
+            Some(layers) => layers,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let mut overlay = Overlay { grid: false, axes: false, labels: false, scale_bar: false };
+    for layer in layers.split(',') {
+        match layer {
+            "grid" => overlay.grid = true,
+            "axes" => overlay.axes = true,
+            "labels" => overlay.labels = true,
+            "scale" => overlay.scale_bar = true,
+            _ => return Err(format!("unknown overlay layer '{}'; expected grid, axes, labels or scale", layer)),
+        }
+    }
+    Ok(Some(overlay))
+}
+
+#[test]
+fn test_parse_option() {
+    assert_eq!(parse_option("overlay"), Ok(Some(ALL)));
+    assert_eq!(parse_option("overlay=axes,scale"),
+               Ok(Some(Overlay { grid: false, axes: true, labels: false, scale_bar: true })));
+    assert_eq!(parse_option("overlays"), Ok(None));
+    assert_eq!(parse_option("rotate=5"), Ok(None));
+    assert!(parse_option("overlay=grid,ticks").is_err());
+}
+
+impl Overlay {
+    // Draw the enabled layers onto 'pixels', an image of size 'bounds' showing 'viewport'.
+    pub fn draw(&self, pixels: &mut [u8], bounds: (usize, usize), viewport: &Viewport) {
+        assert_eq!(pixels.len(), bounds.0 * bounds.1);
+        let original = pixels.to_vec();
+        let mut canvas = Canvas { pixels, original, bounds };
+        if viewport.point_to_pixel(viewport.origin).is_none() {
+            return;
+        }
+
+        let (re_range, im_range) = visible_ranges(bounds, viewport);
+        let step = nice_step((re_range.1 - re_range.0).max(im_range.1 - im_range.0) / 8.0);
+        let text_scale = (bounds.0.min(bounds.1) / 300).max(1);
+        let mut labels = Vec::new();
+
+        // Vertical lines hold 're' constant and are labelled where they leave the
+        // bottom of the image; horizontal ones hold 'im' and are labelled on the left.
+        for (constant_re, range, other) in [(true, re_range, im_range), (false, im_range, re_range)] {
+            let first = (range.0 / step).ceil() as i64;
+            let last = (range.1 / step).floor() as i64;
+            for k in first..=last {
+                let value = k as f64 * step;
+                let (from, to) = if constant_re {
+                    (Complex { re: value, im: other.0 }, Complex { re: value, im: other.1 })
+                } else {
+                    (Complex { re: other.0, im: value }, Complex { re: other.1, im: value })
+                };
+
+                let axis = k == 0;
+                if axis && self.axes {
+                    canvas.line(viewport, from, to, 1, true);
+                } else if self.grid {
+                    canvas.line(viewport, from, to, 3, false);
+                } else if !self.labels {
+                    continue;
+                }
+
+                if self.labels {
+                    let text = format_coordinate(value, step, !constant_re);
+                    let anchor = canvas.line_end(viewport, from, to, |(column, row)| {
+                        if constant_re { row as isize } else { -(column as isize) }
+                    });
+                    if let Some(anchor) = anchor {
+                        labels.push((text, anchor, constant_re));
+                    }
+                }
+            }
+        }
+
+        // Labels go on last so lines never cross them.
+        for (text, (column, row), bottom) in labels {
+            let (width, height) = text_size(&text, text_scale);
+            let (column, row) = if bottom {
+                (column.saturating_sub(width / 2), row.saturating_sub(height + 2 * text_scale))
+            } else {
+                (column + 2 * text_scale, row.saturating_sub(height / 2))
+            };
+            canvas.text(&text, (column, row), text_scale);
+        }
+
+        if self.scale_bar {
+            canvas.scale_bar(viewport, text_scale);
+        }
+    }
+}
+
+// The pixel buffer being annotated, and a copy of it from before any drawing.
+struct Canvas<'a> {
+    pixels: &'a mut [u8],
+    original: Vec<u8>,
+    bounds: (usize, usize),
+}
+
+impl Canvas<'_> {
+    fn pixel(&mut self, column: isize, row: isize) -> Option<&mut u8> {
+        if column < 0 || row < 0 || column as usize >= self.bounds.0 || row as usize >= self.bounds.1 {
+            return None;
+        }
+        Some(&mut self.pixels[row as usize * self.bounds.0 + column as usize])
+    }
+
+    // Set a pixel to black or white, whichever stands out against the rendered image.
+    // Deciding from the original means lines that cross don't cancel each other out.
+    fn contrast(&mut self, column: isize, row: isize) {
+        let bounds = self.bounds;
+        let original = if column >= 0 && row >= 0 && (column as usize) < bounds.0 && (row as usize) < bounds.1 {
+            self.original[row as usize * bounds.0 + column as usize]
+        } else {
+            return;
+        };
+        self.fill(column, row, if original < 128 { 255 } else { 0 });
+    }
+
+    fn fill(&mut self, column: isize, row: isize, value: u8) {
+        if let Some(pixel) = self.pixel(column, row) {
+            *pixel = value;
+        }
+    }
+
+    // The pixels, clipped to the image, on the segment between two points of the plane.
+    fn segment(&self, viewport: &Viewport, from: Complex<f64>, to: Complex<f64>) -> Vec<(usize, usize)> {
+        let (Some(a), Some(b)) = (viewport.point_to_pixel(from), viewport.point_to_pixel(to)) else {
+            return Vec::new();
+        };
+        let steps = (b.0 - a.0).abs().max((b.1 - a.1).abs()).ceil().max(1.0) as usize;
+        let mut pixels = Vec::new();
+        for i in 0..=steps {
+            let t = i as f64 / steps as f64;
+            let (column, row) = ((a.0 + (b.0 - a.0) * t).floor(), (a.1 + (b.1 - a.1) * t).floor());
+            if column >= 0.0 && row >= 0.0 && (column as usize) < self.bounds.0 && (row as usize) < self.bounds.1 {
+                pixels.push((column as usize, row as usize));
+            }
+        }
+        pixels.dedup();
+        pixels
+    }
+
+    // Draw a line, every 'dash'-th pixel of it, one pixel wide or three if 'bold'.
+    fn line(&mut self, viewport: &Viewport, from: Complex<f64>, to: Complex<f64>, dash: usize, bold: bool) {
+        for (i, (column, row)) in self.segment(viewport, from, to).into_iter().enumerate() {
+            if i % dash != 0 {
+                continue;
+            }
+            let (column, row) = (column as isize, row as isize);
+            self.contrast(column, row);
+            if bold {
+                self.contrast(column + 1, row);
+                self.contrast(column, row + 1);
+            }
+        }
+    }
+
+    // The visible pixel of a segment that maximizes 'key'.
+    fn line_end(&self, viewport: &Viewport, from: Complex<f64>, to: Complex<f64>,
+                key: impl Fn((usize, usize)) -> isize) -> Option<(usize, usize)> {
+        self.segment(viewport, from, to).into_iter().max_by_key(|&p| key(p))
+    }
+
+    // White text on a black box, with its top-left corner at 'at'.
+    fn text(&mut self, text: &str, at: (usize, usize), scale: usize) {
+        let (width, height) = text_size(text, scale);
+        let (left, top) = (at.0 as isize, at.1 as isize);
+        for row in -1..=height as isize {
+            for column in -1..=width as isize {
+                self.fill(left + column, top + row, 0);
+            }
+        }
+
+        for (i, ch) in text.chars().enumerate() {
+            let glyph = glyph(ch);
+            let glyph_left = left + (i * 4 * scale) as isize;
+            for (row, bits) in glyph.iter().enumerate() {
+                for column in 0..3 {
+                    if bits & (0b100 >> column) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            self.fill(glyph_left + (column * scale + dx) as isize,
+                                      top + (row * scale + dy) as isize, 255);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+    A horizontal bar in the top-right corner about a fifth of the image wide,
+    rounded to a nice distance on the complex plane and labelled with it. The
+    bottom and left edges are left to the grid labels.
+     */
+    fn scale_bar(&mut self, viewport: &Viewport, text_scale: usize) {
+        let per_pixel = viewport.column_step.norm();
+        if per_pixel == 0.0 || !per_pixel.is_finite() {
+            return;
+        }
+        let distance = nice_step(self.bounds.0 as f64 / 5.0 * per_pixel);
+        let length = (distance / per_pixel).round() as isize;
+        let margin = 4 * text_scale as isize;
+        let right = self.bounds.0 as isize - margin - 1;
+        let left = right - length;
+
+        let text = format_coordinate(distance, distance, false);
+        let (width, height) = text_size(&text, text_scale);
+        let row = margin + height as isize + 2 * text_scale as isize + 3;
+        for column in left - 1..=right + 1 {
+            for r in row - 3..=row + 1 {
+                self.fill(column, r, 0);
+            }
+        }
+        for column in left..=right {
+            self.fill(column, row, 255);
+        }
+        for r in row - 2..row {
+            self.fill(left, r, 255);
+            self.fill(right, r, 255);
+        }
+        let label_column = (left + right) / 2 - width as isize / 2;
+        if label_column >= 0 {
+            self.text(&text, (label_column as usize, margin as usize), text_scale);
+        }
+    }
+}
+
+// The ranges of 're' and 'im' covered by the image, as (min, max) pairs.
+fn visible_ranges(bounds: (usize, usize), viewport: &Viewport) -> ((f64, f64), (f64, f64)) {
+    let (w, h) = (bounds.0 as f64, bounds.1 as f64);
+    let corners = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].map(|p| viewport.pixel_to_point(p));
+    let range = |f: fn(&Complex<f64>) -> f64| {
+        corners.iter().map(f).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), x| (lo.min(x), hi.max(x)))
+    };
+    (range(|z| z.re), range(|z| z.im))
+}
+
+// The smallest of 1, 2 or 5 times a power of ten that is at least 'x'.
+fn nice_step(x: f64) -> f64 {
+    let power = 10f64.powf(x.log10().floor());
+    for factor in [1.0, 2.0, 5.0, 10.0] {
+        if factor * power >= x * (1.0 - 1e-9) {
+            return factor * power;
+        }
+    }
+    10.0 * power
+}
+
+#[test]
+fn test_nice_step() {
+    assert_eq!(nice_step(1.0), 1.0);
+    assert_eq!(nice_step(0.3), 0.5);
+    assert_eq!(nice_step(1.5), 2.0);
+    assert_eq!(nice_step(7.0), 10.0);
+    assert!((nice_step(0.00012) - 0.0002).abs() < 1e-15);
+}
+
+// 'value' with just enough decimals to tell grid lines 'step' apart.
+fn format_coordinate(value: f64, step: f64, imaginary: bool) -> String {
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    let value = if value == 0.0 { 0.0 } else { value }; // no "-0"
+    format!("{:.*}{}", decimals, value, if imaginary { "i" } else { "" })
+}
+
+#[test]
+fn test_format_coordinate() {
+    assert_eq!(format_coordinate(-0.5, 0.5, false), "-0.5");
+    assert_eq!(format_coordinate(2.0, 1.0, true), "2i");
+    assert_eq!(format_coordinate(-0.0, 0.02, false), "0.00");
+    assert_eq!(format_coordinate(-0.7452, 0.0002, true), "-0.7452i");
+}
+
+fn text_size(text: &str, scale: usize) -> (usize, usize) {
+    let count = text.chars().count();
+    ((count * 4).saturating_sub(1) * scale, 5 * scale)
+}
+
+// A 3x5 bitmap for each character a label can contain, one row per byte.
+fn glyph(ch: char) -> [u8; 5] {
+    match ch {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        'i' => [0b010, 0b000, 0b010, 0b010, 0b010],
+        _ => [0; 5],
+    }
+}
+
+#[test]
+fn test_draw_axes() {
+    let bounds = (101, 81);
+    let viewport = Viewport::from_corners(bounds, Complex { re: -1.01, im: 0.81 }, Complex { re: 1.01, im: -0.81 });
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    let axes = Overlay { grid: false, axes: true, labels: false, scale_bar: false };
+    axes.draw(&mut pixels, bounds, &viewport);
+
+    // The imaginary axis runs down the middle column and the real axis across the middle row.
+    assert!((0..bounds.1).all(|row| pixels[row * bounds.0 + 50] == 255));
+    assert!((0..bounds.0).all(|column| pixels[40 * bounds.0 + column] == 255));
+    assert_eq!(pixels[10 * bounds.0 + 10], 0);
+
+    // Rotated, the axes cross at the center but no longer fill a row.
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    let rotated = viewport.rotated(viewport.center(bounds), 30.0);
+    axes.draw(&mut pixels, bounds, &rotated);
+    assert_eq!(pixels[40 * bounds.0 + 50], 255);
+    assert!((0..bounds.0).any(|column| pixels[40 * bounds.0 + column] == 0));
+
+    // Labels near the edges must not write outside the buffer.
+    let mut pixels = vec![200; bounds.0 * bounds.1];
+    ALL.draw(&mut pixels, bounds, &rotated);
+    assert!(pixels.contains(&0) && pixels.contains(&255));
+}
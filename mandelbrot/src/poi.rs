@@ -0,0 +1,436 @@
+use num::Complex;
+use std::io::{self, BufRead, Write};
+
+use crate::{escape_time, parse_complex, parse_pair, pixel_to_point};
+
+/*
+Point-of-interest finder
+
+Searching for zoom targets by eye is slow, so 'mandelbrot poi' samples a
+region with 'escape_time' and uses the samples to seed Newton's method on two
+kinds of special points:
+
+- Minibrots: the nucleus 'c' of a hyperbolic component of period 'p' solves
+  f_c^p(0) = 0, where f_c(z) = z * z + c. The period comes from the "atom
+  domain" of the seed, i.e. the iteration at which |z| got closest to zero.
+
+- Spiral centers: Misiurewicz points, where the orbit of 0 becomes periodic
+  after 'k' steps, so f_c^(k + p)(0) = f_c^k(0). The preperiod and period
+  come from the closest near-repeat in the seed's orbit.
+
+Every candidate that converges inside the region is reported once, biggest
+first, together with a suggested view width and the UPPERLEFT LOWERRIGHT
+arguments that render it. Entering a candidate's number then zooms to that
+view and searches it again, so you can follow a minibrot or spiral down until
+f64 runs out of precision; an empty line or end of input quits.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Minibrot,
+    Spiral,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    kind: Kind,
+    center: Complex<f64>,
+    preperiod: usize,
+    period: usize,
+    // Suggested width of the view on the complex plane.
+    width: f64,
+}
+
+// Longest period we try to solve for; higher periods rarely converge from a coarse seed.
+const MAX_PERIOD: usize = 256;
+
+// Longest preperiod and period considered for spiral centers.
+const MAX_SPIRAL_STEPS: usize = 16;
+
+// Newton steps before giving up on a seed.
+const NEWTON_STEPS: usize = 64;
+
+// How many candidates are printed at most.
+const MAX_CANDIDATES: usize = 25;
+
+fn print_usage(program: &str) {
+    eprintln!("Usage: {} poi UPPERLEFT LOWERRIGHT [SAMPLES] [LIMIT]", program);
+    eprintln!("Example: {} poi -2,1.2 0.6,-1.2 200x150 1000", program);
+    eprintln!("After each search, enter a candidate's number to zoom in and search again.");
+}
+
+// Entry point for 'mandelbrot poi ...'; 'args' is the full command line.
+pub fn run(args: &[String]) {
+    if args.len() < 4 || args.len() > 6 {
+        print_usage(&args[0]);
+        std::process::exit(1);
+    }
+
+    let mut upper_left = parse_complex(&args[2]).expect("error parsing upper left corner point");
+    let mut lower_right = parse_complex(&args[3]).expect("error parsing lower right corner point");
+    let samples = match args.get(4) {
+        None => (200, 150),
+        Some(s) => parse_pair(s, 'x').expect("error parsing sample grid dimensions"),
+    };
+    let limit = match args.get(5) {
+        None => 1000,
+        Some(s) => s.parse().expect("error parsing iteration limit"),
+    };
+
+    let aspect = samples.1 as f64 / samples.0 as f64;
+    let stdin = io::stdin();
+    loop {
+        let candidates = find_candidates(samples, upper_left, lower_right, limit);
+        if candidates.is_empty() {
+            println!("no points of interest found; try a larger SAMPLES grid or LIMIT");
+            return;
+        }
+        print_candidates(&candidates, aspect);
+
+        let shown = candidates.len().min(MAX_CANDIDATES);
+        let chosen = match choose(&mut stdin.lock(), shown) {
+            Some(chosen) => &candidates[chosen],
+            None => return,
+        };
+        (upper_left, lower_right) = corners(chosen.center, chosen.width, aspect);
+        println!();
+        println!("searching {},{} {},{}", upper_left.re, upper_left.im, lower_right.re, lower_right.im);
+    }
+}
+
+fn print_candidates(candidates: &[Candidate], aspect: f64) {
+    println!("{:>3} {:<8} {:>44} {:>9} {:>6} {:>12}  corners",
+             "#", "kind", "center", "preperiod", "period", "width");
+    for (number, candidate) in candidates.iter().take(MAX_CANDIDATES).enumerate() {
+        let kind = match candidate.kind {
+            Kind::Minibrot => "minibrot",
+            Kind::Spiral => "spiral",
+        };
+        let (ul, lr) = corners(candidate.center, candidate.width, aspect);
+        println!("{:>3} {:<8} {:>44} {:>9} {:>6} {:>12.3e}  {},{} {},{}",
+                 number + 1, kind, format!("{},{}", candidate.center.re, candidate.center.im),
+                 candidate.preperiod, candidate.period, candidate.width,
+                 ul.re, ul.im, lr.re, lr.im);
+    }
+    if candidates.len() > MAX_CANDIDATES {
+        println!("... and {} more", candidates.len() - MAX_CANDIDATES);
+    }
+}
+
+/*
+Ask which of the 'count' listed candidates to zoom to, reading answers from
+'input' until one is valid. Returns the candidate's index, or None if the
+user enters an empty line or 'q', or the input ends.
+ */
+fn choose(input: &mut impl BufRead, count: usize) -> Option<usize> {
+    loop {
+        eprint!("zoom to candidate (1-{}, Enter to quit): ", count);
+        io::stderr().flush().ok();
+        let mut line = String::new();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            return None;
+        }
+        let answer = line.trim();
+        if answer.is_empty() || answer == "q" {
+            return None;
+        }
+        match answer.parse::<usize>() {
+            Ok(number) if number >= 1 && number <= count => return Some(number - 1),
+            _ => eprintln!("no candidate '{}'", answer),
+        }
+    }
+}
+
+// The upper-left and lower-right corners of a view 'width' wide centered on 'center'.
+fn corners(center: Complex<f64>, width: f64, aspect: f64) -> (Complex<f64>, Complex<f64>) {
+    let half = Complex { re: width / 2.0, im: width * aspect / 2.0 };
+    (Complex { re: center.re - half.re, im: center.im + half.im },
+     Complex { re: center.re + half.re, im: center.im - half.im })
+}
+
+/*
+Sample the region on a 'samples' grid and return every distinct point of
+interest found inside it, largest first.
+
+Seeds are interior samples on the edge of the set and escaping samples whose
+escape time is a local maximum: both sit close to the minibrots and spirals
+we are looking for.
+ */
+fn find_candidates(samples: (usize, usize),
+                   upper_left: Complex<f64>,
+                   lower_right: Complex<f64>,
+                   limit: usize) -> Vec<Candidate> {
+    let (columns, rows) = samples;
+    let counts: Vec<Option<usize>> = (0..rows * columns)
+        .map(|i| escape_time(pixel_to_point(samples, (i % columns, i / columns), upper_left, lower_right), limit))
+        .collect();
+    // Interior points count as "escaped at the limit" when comparing neighbours.
+    let count = |column: usize, row: usize| counts[row * columns + column].unwrap_or(limit);
+
+    let pixel_width = (lower_right.re - upper_left.re) / columns as f64;
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let here = count(column, row);
+            let mut on_edge = false;
+            let mut local_max = true;
+            for (dc, dr) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                let (c, r) = (column as isize + dc, row as isize + dr);
+                if c < 0 || r < 0 || c >= columns as isize || r >= rows as isize {
+                    continue;
+                }
+                let there = count(c as usize, r as usize);
+                on_edge |= there < limit;
+                local_max &= there <= here;
+            }
+
+            let interior = counts[row * columns + column].is_none();
+            let is_seed = if interior { on_edge } else { local_max };
+            if !is_seed {
+                continue;
+            }
+
+            let seed = pixel_to_point(samples, (column, row), upper_left, lower_right);
+            if let Some(period) = atom_period(seed, limit) {
+                if let Some(center) = nucleus(seed, period) {
+                    // Newton may land on a divisor of the period guessed from the seed.
+                    let period = exact_period(center, period).unwrap_or(period);
+                    add_candidate(&mut candidates, Candidate {
+                        kind: Kind::Minibrot,
+                        center,
+                        preperiod: 0,
+                        period,
+                        width: 3.0 * minibrot_size(center, period),
+                    }, pixel_width);
+                }
+            }
+            if !interior {
+                if let Some((preperiod, period)) = near_repeat(seed) {
+                    if let Some(center) = misiurewicz(seed, preperiod, period) {
+                        let (preperiod, period) = preperiods(center).unwrap_or((preperiod, period));
+                        add_candidate(&mut candidates, Candidate {
+                            kind: Kind::Spiral,
+                            center,
+                            preperiod,
+                            period,
+                            // Spirals have no natural size; zoom until the sample grid would resolve them.
+                            width: 4.0 * pixel_width,
+                        }, pixel_width);
+                    }
+                }
+            }
+        }
+    }
+
+    let (min_re, max_re) = (upper_left.re.min(lower_right.re), upper_left.re.max(lower_right.re));
+    let (min_im, max_im) = (upper_left.im.min(lower_right.im), upper_left.im.max(lower_right.im));
+    candidates.retain(|c| c.center.re >= min_re && c.center.re <= max_re &&
+                          c.center.im >= min_im && c.center.im <= max_im);
+    candidates.sort_by(|a, b| b.width.partial_cmp(&a.width).unwrap());
+    candidates
+}
+
+// Add 'candidate' unless an equivalent point is already known.
+fn add_candidate(candidates: &mut Vec<Candidate>, candidate: Candidate, pixel_width: f64) {
+    let tolerance = pixel_width.abs() * 1e-3;
+    let duplicate = candidates.iter().any(|c| {
+        c.kind == candidate.kind && c.period == candidate.period &&
+            (c.center - candidate.center).norm() < tolerance
+    });
+    if !duplicate && candidate.width.is_finite() && candidate.width > 0.0 {
+        candidates.push(candidate);
+    }
+}
+
+// The iteration, below 'limit', at which the orbit of 0 comes closest to 0.
+fn atom_period(c: Complex<f64>, limit: usize) -> Option<usize> {
+    let mut z = c;
+    let mut closest = f64::INFINITY;
+    let mut period = None;
+    for i in 1..limit.min(MAX_PERIOD + 1) {
+        let distance = z.norm_sqr();
+        if distance > 4.0 {
+            break;
+        }
+        if distance < closest {
+            closest = distance;
+            period = Some(i);
+        }
+        z = z * z + c;
+    }
+    period
+}
+
+// The smallest period up to 'max' for which the orbit of 0 returns to 0.
+fn exact_period(c: Complex<f64>, max: usize) -> Option<usize> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    for p in 1..=max {
+        z = z * z + c;
+        if z.norm() < 1e-9 {
+            return Some(p);
+        }
+    }
+    None
+}
+
+// Solve f_c^period(0) = 0 for 'c' with Newton's method, starting from 'seed'.
+fn nucleus(seed: Complex<f64>, period: usize) -> Option<Complex<f64>> {
+    let mut c = seed;
+    for _ in 0..NEWTON_STEPS {
+        // z is f_c^n(0) and dz its derivative with respect to c.
+        let mut z = Complex { re: 0.0, im: 0.0 };
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        for _ in 0..period {
+            dz = 2.0 * z * dz + 1.0;
+            z = z * z + c;
+        }
+        let step = z / dz;
+        if !step.re.is_finite() || !step.im.is_finite() {
+            return None;
+        }
+        c -= step;
+        if step.norm() < 1e-15 * c.norm().max(1.0) {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/*
+Estimate the size of the minibrot with nucleus 'c' and period 'period',
+scaled so that the whole Mandelbrot set (period 1) has size 1.
+ */
+fn minibrot_size(c: Complex<f64>, period: usize) -> f64 {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    let mut l = Complex { re: 1.0, im: 0.0 };
+    let mut b = Complex { re: 1.0, im: 0.0 };
+    for _ in 1..period {
+        z = z * z + c;
+        l = 2.0 * z * l;
+        b += 1.0 / l;
+    }
+    (1.0 / (b * l * l)).norm()
+}
+
+// The preperiod and period of the closest near-repeat in the orbit of 0.
+fn near_repeat(c: Complex<f64>) -> Option<(usize, usize)> {
+    let orbit = orbit(c, 2 * MAX_SPIRAL_STEPS)?;
+    let mut best = None;
+    let mut closest = f64::INFINITY;
+    for preperiod in 1..=MAX_SPIRAL_STEPS {
+        for period in 1..=MAX_SPIRAL_STEPS {
+            let distance = (orbit[preperiod + period] - orbit[preperiod]).norm();
+            if distance < closest {
+                closest = distance;
+                best = Some((preperiod, period));
+            }
+        }
+    }
+    best
+}
+
+// The exact preperiod and period of a Misiurewicz point, smallest first.
+fn preperiods(c: Complex<f64>) -> Option<(usize, usize)> {
+    let orbit = orbit(c, 2 * MAX_SPIRAL_STEPS)?;
+    for preperiod in 1..=MAX_SPIRAL_STEPS {
+        for period in 1..=MAX_SPIRAL_STEPS {
+            if (orbit[preperiod + period] - orbit[preperiod]).norm() < 1e-9 {
+                return Some((preperiod, period));
+            }
+        }
+    }
+    None
+}
+
+// The first 'length' + 1 points of the orbit of 0, or None if it escapes.
+fn orbit(c: Complex<f64>, length: usize) -> Option<Vec<Complex<f64>>> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    let mut orbit = vec![z];
+    for _ in 0..length {
+        z = z * z + c;
+        if z.norm_sqr() > 4.0 {
+            return None;
+        }
+        orbit.push(z);
+    }
+    Some(orbit)
+}
+
+/*
+Solve f_c^(preperiod + period)(0) = f_c^preperiod(0) for 'c' with Newton's
+method, rejecting solutions that are really periodic points (where the orbit
+hits 0 and so repeats trivially).
+ */
+fn misiurewicz(seed: Complex<f64>, preperiod: usize, period: usize) -> Option<Complex<f64>> {
+    let mut c = seed;
+    for _ in 0..NEWTON_STEPS {
+        let mut z = Complex { re: 0.0, im: 0.0 };
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        let (mut zk, mut dzk) = (z, dz);
+        for i in 0..preperiod + period {
+            if i == preperiod {
+                zk = z;
+                dzk = dz;
+            }
+            dz = 2.0 * z * dz + 1.0;
+            z = z * z + c;
+        }
+        let step = (z - zk) / (dz - dzk);
+        if !step.re.is_finite() || !step.im.is_finite() {
+            return None;
+        }
+        c -= step;
+        if step.norm() < 1e-15 * c.norm().max(1.0) {
+            return match exact_period(c, preperiod + period) {
+                Some(_) => None,
+                None => Some(c),
+            };
+        }
+    }
+    None
+}
+
+#[test]
+fn test_nucleus() {
+    let c = nucleus(Complex { re: -0.9, im: 0.1 }, 2).unwrap();
+    assert!((c - Complex { re: -1.0, im: 0.0 }).norm() < 1e-12);
+
+    let c = nucleus(Complex { re: -0.12, im: 0.74 }, 3).unwrap();
+    assert!((c - Complex { re: -0.12256116687665, im: 0.74486176661974 }).norm() < 1e-12);
+    assert_eq!(exact_period(c, 10), Some(3));
+
+    assert!((minibrot_size(Complex { re: 0.0, im: 0.0 }, 1) - 1.0).abs() < 1e-12);
+    assert!(minibrot_size(c, 3) < minibrot_size(Complex { re: -1.0, im: 0.0 }, 2));
+}
+
+#[test]
+fn test_misiurewicz() {
+    // The orbit of 0 under z * z + i is 0, i, -1 + i, -i, -1 + i, -i, ...
+    let c = misiurewicz(Complex { re: 0.05, im: 0.95 }, 2, 2).unwrap();
+    assert!((c - Complex { re: 0.0, im: 1.0 }).norm() < 1e-12);
+    assert_eq!(preperiods(c), Some((2, 2)));
+
+    // Period-2 nucleus: repeats, but only because the orbit passes through 0.
+    assert_eq!(misiurewicz(Complex { re: -1.0, im: 0.0 }, 2, 2), None);
+}
+
+#[test]
+fn test_find_candidates() {
+    // The period 3 minibrot on the real axis, at about -1.7549.
+    let candidates = find_candidates((80, 60), Complex { re: -1.80, im: 0.03 },
+                                     Complex { re: -1.72, im: -0.03 }, 500);
+    let biggest = &candidates[0];
+    assert_eq!(biggest.kind, Kind::Minibrot);
+    assert_eq!(biggest.period, 3);
+    assert!((biggest.center - Complex { re: -1.7548776662466927, im: 0.0 }).norm() < 1e-9);
+}
+
+#[test]
+fn test_choose() {
+    assert_eq!(choose(&mut io::Cursor::new("2\n"), 3), Some(1));
+    assert_eq!(choose(&mut io::Cursor::new("0\nx\n4\n3\n"), 3), Some(2));
+    assert_eq!(choose(&mut io::Cursor::new("\n1\n"), 3), None);
+    assert_eq!(choose(&mut io::Cursor::new(" q \n"), 3), None);
+    assert_eq!(choose(&mut io::Cursor::new(""), 3), None);
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+/*
+Render statistics
+
+'render' counts what it did while it works: how many iterations of
+z = z * z + c it ran in total, how many pixels escaped and how many were
+taken to be in the set, and the largest escape count it saw. The band
+workers add how long each band took on the wall clock, which is usually
+what explains a slow render: the bands crossing the set do far more work
+than the ones outside it.
+
+A 'Report' gathers all of this for one image and prints it either as a
+human summary or as JSON, so numbers can be compared across versions.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderStats {
+    pub total_iterations: u64,
+    pub escaped: usize,
+    pub interior: usize,
+    // The largest escape count among escaped pixels.
+    pub max_iterations: usize,
+}
+
+impl RenderStats {
+    // Count one pixel; 'count' is what 'escape_time' returned for it.
+    pub fn record(&mut self, count: Option<usize>, limit: usize) {
+        match count {
+            None => {
+                self.interior += 1;
+                self.total_iterations += limit as u64;
+            }
+            Some(count) => {
+                self.escaped += 1;
+                self.total_iterations += count as u64;
+                self.max_iterations = self.max_iterations.max(count);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &RenderStats) {
+        self.total_iterations += other.total_iterations;
+        self.escaped += other.escaped;
+        self.interior += other.interior;
+        self.max_iterations = self.max_iterations.max(other.max_iterations);
+    }
+}
+
+#[test]
+fn test_render_stats() {
+    let mut a = RenderStats::default();
+    a.record(Some(3), 10);
+    a.record(None, 10);
+    assert_eq!(a, RenderStats { total_iterations: 13, escaped: 1, interior: 1, max_iterations: 3 });
+
+    let mut b = RenderStats::default();
+    b.record(Some(7), 10);
+    a.merge(&b);
+    assert_eq!(a, RenderStats { total_iterations: 20, escaped: 2, interior: 1, max_iterations: 7 });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+/*
+Parse a 'stats' option, written without any leading dashes: 'stats' or
+'stats=text' for the human summary, 'stats=json' for JSON. Returns Ok(None)
+for other options.
+ */
+pub fn parse_option(option: &str) -> Result<Option<Format>, String> {
+    match option {
+        "stats" | "stats=text" => Ok(Some(Format::Human)),
+        "stats=json" => Ok(Some(Format::Json)),
+        _ if option.starts_with("stats=") => Err(format!("unknown stats format '{}'; expected text or json", &option[6..])),
+        _ => Ok(None),
+    }
+}
+
+// One band of the image as a worker rendered it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandStats {
+    pub top: usize,
+    pub height: usize,
+    pub wall: Duration,
+    pub stats: RenderStats,
+}
+
+// Everything measured while rendering one image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub bounds: (usize, usize),
+    pub limit: usize,
+    pub threads: usize,
+    pub wall: Duration,
+    pub bands: Vec<BandStats>,
+}
+
+impl Report {
+    pub fn totals(&self) -> RenderStats {
+        let mut totals = RenderStats::default();
+        for band in &self.bands {
+            totals.merge(&band.stats);
+        }
+        totals
+    }
+
+    pub fn print(&self, format: Format) {
+        match format {
+            Format::Human => print!("{}", self.human()),
+            Format::Json => println!("{}", self.json()),
+        }
+    }
+
+    pub fn human(&self) -> String {
+        let totals = self.totals();
+        let pixels = (self.bounds.0 * self.bounds.1).max(1);
+        let mut out = String::new();
+        out += &format!("image:       {}x{} pixels, limit {}, {} threads\n",
+                        self.bounds.0, self.bounds.1, self.limit, self.threads);
+        out += &format!("wall time:   {:.1} ms\n", millis(self.wall));
+        out += &format!("iterations:  {} total, {:.1} per pixel, max escape {}\n",
+                        totals.total_iterations, totals.total_iterations as f64 / pixels as f64,
+                        totals.max_iterations);
+        out += &format!("pixels:      {} escaped, {} interior ({:.1}% interior)\n",
+                        totals.escaped, totals.interior, 100.0 * totals.interior as f64 / pixels as f64);
+        out += "bands:       rows           wall ms    iterations\n";
+        for band in &self.bands {
+            out += &format!("             {:>5}-{:<5} {:>10.1} {:>13}\n",
+                            band.top, band.top + band.height, millis(band.wall), band.stats.total_iterations);
+        }
+        out
+    }
+
+    // The report as a single-line JSON object.
+    pub fn json(&self) -> String {
+        let totals = self.totals();
+        let bands: Vec<String> = self.bands.iter().map(|band| {
+            format!("{{\"top\":{},\"height\":{},\"wall_ms\":{:.3},\"total_iterations\":{},\
+                     \"escaped\":{},\"interior\":{},\"max_iterations\":{}}}",
+                    band.top, band.height, millis(band.wall), band.stats.total_iterations,
+                    band.stats.escaped, band.stats.interior, band.stats.max_iterations)
+        }).collect();
+        format!("{{\"version\":\"{}\",\"width\":{},\"height\":{},\"limit\":{},\"threads\":{},\
+                 \"wall_ms\":{:.3},\"total_iterations\":{},\"escaped\":{},\"interior\":{},\
+                 \"max_iterations\":{},\"bands\":[{}]}}",
+                env!("CARGO_PKG_VERSION"), self.bounds.0, self.bounds.1, self.limit, self.threads,
+                millis(self.wall), totals.total_iterations, totals.escaped, totals.interior,
+                totals.max_iterations, bands.join(","))
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[test]
+fn test_report() {
+    let stats = RenderStats { total_iterations: 40, escaped: 3, interior: 1, max_iterations: 9 };
+    let report = Report {
+        bounds: (2, 2),
+        limit: 10,
+        threads: 2,
+        wall: Duration::from_millis(5),
+        bands: vec![
+            BandStats { top: 0, height: 1, wall: Duration::from_millis(2), stats },
+            BandStats { top: 1, height: 1, wall: Duration::from_millis(3), stats: RenderStats::default() },
+        ],
+    };
+    assert_eq!(report.totals(), stats);
+    assert!(report.human().contains("3 escaped, 1 interior (25.0% interior)"));
+    assert_eq!(report.json(), format!(
+        "{{\"version\":\"{}\",\"width\":2,\"height\":2,\"limit\":10,\"threads\":2,\"wall_ms\":5.000,\
+         \"total_iterations\":40,\"escaped\":3,\"interior\":1,\"max_iterations\":9,\"bands\":[\
+         {{\"top\":0,\"height\":1,\"wall_ms\":2.000,\"total_iterations\":40,\"escaped\":3,\"interior\":1,\"max_iterations\":9}},\
+         {{\"top\":1,\"height\":1,\"wall_ms\":3.000,\"total_iterations\":0,\"escaped\":0,\"interior\":0,\"max_iterations\":0}}]}}",
+        env!("CARGO_PKG_VERSION")));
+
+    assert_eq!(parse_option("stats"), Ok(Some(Format::Human)));
+    assert_eq!(parse_option("stats=json"), Ok(Some(Format::Json)));
+    assert!(parse_option("stats=xml").is_err());
+    assert_eq!(parse_option("overlay"), Ok(None));
+}
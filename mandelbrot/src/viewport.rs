@@ -0,0 +1,196 @@
+use num::Complex;
+
+/*
+A general affine mapping between pixel space and the complex plane.
+
+'pixel_to_point' only handles axis-aligned rectangles given by two corners.
+A Viewport instead stores where pixel (0, 0) lands and how far one step to
+the right and one step down move on the complex plane:
+
+    point = origin + column * column_step + row * row_step
+
+With 'column_step' and 'row_step' free to point anywhere this covers
+rotation, flips and skew as well as plain rectangles. Because the mapping is
+affine it can be inverted, so 'point_to_pixel' takes a point on the complex
+plane back to the (fractional) pixel that shows it, which is what overlays
+need to draw at given coordinates.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub origin: Complex<f64>,
+    pub column_step: Complex<f64>,
+    pub row_step: Complex<f64>,
+}
+
+// A 2x2 real matrix acting on points of the complex plane, as [[a, b], [c, d]].
+pub type Matrix = [[f64; 2]; 2];
+
+impl Viewport {
+    // The axis-aligned viewport 'pixel_to_point' describes.
+    pub fn from_corners(bounds: (usize, usize),
+                        upper_left: Complex<f64>,
+                        lower_right: Complex<f64>) -> Viewport {
+        Viewport {
+            origin: upper_left,
+            column_step: Complex { re: (lower_right.re - upper_left.re) / bounds.0 as f64, im: 0.0 },
+            row_step: Complex { re: 0.0, im: (lower_right.im - upper_left.im) / bounds.1 as f64 },
+        }
+    }
+
+    pub fn pixel_to_point(&self, pixel: (f64, f64)) -> Complex<f64> {
+        self.origin + self.column_step * pixel.0 + self.row_step * pixel.1
+    }
+
+    // The (column, row) showing 'point', or None if the viewport is degenerate.
+    pub fn point_to_pixel(&self, point: Complex<f64>) -> Option<(f64, f64)> {
+        let (a, b) = (self.column_step.re, self.row_step.re);
+        let (c, d) = (self.column_step.im, self.row_step.im);
+        let determinant = a * d - b * c;
+        if determinant == 0.0 || !determinant.is_finite() {
+            return None;
+        }
+        let offset = point - self.origin;
+        Some(((d * offset.re - b * offset.im) / determinant,
+              (a * offset.im - c * offset.re) / determinant))
+    }
+
+    // The point at the middle of an image of size 'bounds'.
+    pub fn center(&self, bounds: (usize, usize)) -> Complex<f64> {
+        self.pixel_to_point((bounds.0 as f64 / 2.0, bounds.1 as f64 / 2.0))
+    }
+
+    // The viewport of the band of rows starting at row 'top'.
+    pub fn band(&self, top: usize) -> Viewport {
+        Viewport { origin: self.origin + self.row_step * top as f64, ..*self }
+    }
+
+    // Apply 'matrix' to the complex plane, keeping 'center' fixed.
+    pub fn transformed(&self, center: Complex<f64>, matrix: Matrix) -> Viewport {
+        let apply = |z: Complex<f64>| Complex {
+            re: matrix[0][0] * z.re + matrix[0][1] * z.im,
+            im: matrix[1][0] * z.re + matrix[1][1] * z.im,
+        };
+        Viewport {
+            origin: center + apply(self.origin - center),
+            column_step: apply(self.column_step),
+            row_step: apply(self.row_step),
+        }
+    }
+
+    // Rotate the view counter-clockwise by 'degrees' around 'center'.
+    pub fn rotated(&self, center: Complex<f64>, degrees: f64) -> Viewport {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        self.transformed(center, [[cos, -sin], [sin, cos]])
+    }
+}
+
+#[test]
+fn test_viewport_from_corners() {
+    let upper_left = Complex { re: -1.0, im: 1.0 };
+    let lower_right = Complex { re: 1.0, im: -1.0 };
+    let viewport = Viewport::from_corners((100, 200), upper_left, lower_right);
+    for pixel in [(0, 0), (25, 175), (100, 200), (3, 7)] {
+        assert_eq!(viewport.pixel_to_point((pixel.0 as f64, pixel.1 as f64)),
+                   crate::pixel_to_point((100, 200), pixel, upper_left, lower_right));
+    }
+    assert_eq!(viewport.point_to_pixel(Complex { re: -0.5, im: -0.75 }), Some((25.0, 175.0)));
+    assert_eq!(viewport.band(175).origin, Complex { re: -1.0, im: -0.75 });
+
+    let degenerate = Viewport::from_corners((10, 10), upper_left, Complex { re: 1.0, im: 1.0 });
+    assert_eq!(degenerate.point_to_pixel(upper_left), None);
+}
+
+#[test]
+fn test_viewport_transforms() {
+    let close = |a: Complex<f64>, b: Complex<f64>| (a - b).norm() < 1e-12;
+    let viewport = Viewport::from_corners((100, 100), Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let center = viewport.center((100, 100));
+    assert_eq!(center, Complex { re: 0.0, im: 0.0 });
+
+    // A quarter turn puts the top-left corner at the bottom-left.
+    let rotated = viewport.rotated(center, 90.0);
+    assert!(close(rotated.pixel_to_point((0.0, 0.0)), Complex { re: -1.0, im: -1.0 }));
+    assert!(close(rotated.center((100, 100)), center));
+
+    let flipped = viewport.transformed(center, [[-1.0, 0.0], [0.0, 1.0]]);
+    assert!(close(flipped.pixel_to_point((0.0, 0.0)), Complex { re: 1.0, im: 1.0 }));
+
+    let skewed = viewport.transformed(center, [[1.0, 0.5], [0.0, 1.0]]);
+    assert!(close(skewed.pixel_to_point((0.0, 0.0)), Complex { re: -0.5, im: 1.0 }));
+
+    // Every transform can be undone by 'point_to_pixel'.
+    for v in [rotated, flipped, skewed, viewport.rotated(center, 33.0)] {
+        let (column, row) = v.point_to_pixel(v.pixel_to_point((12.0, 87.5))).unwrap();
+        assert!((column - 12.0).abs() < 1e-9 && (row - 87.5).abs() < 1e-9);
+    }
+}
+
+/*
+Apply one viewport option, written without any leading dashes:
+
+    rotate=DEG      rotate counter-clockwise around the center
+    flip-x          mirror left to right
+    flip-y          mirror top to bottom
+    skew=K          shear horizontally by K times the imaginary offset
+    affine=A,B,C,D  apply the matrix [[A, B], [C, D]] around the center
+
+Returns Ok(None) if 'option' is not a viewport option at all, so callers can
+handle their own options first.
+ */
+pub fn apply_option(viewport: Viewport, center: Complex<f64>, option: &str) -> Result<Option<Viewport>, String> {
+    let (name, value) = match option.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (option, None),
+    };
+    let number = |value: Option<&str>| -> Result<f64, String> {
+        value.and_then(|v| v.parse().ok())
+            .ok_or_else(|| format!("option '{}' needs a numeric value", name))
+    };
+    let no_value = || -> Result<(), String> {
+        match value {
+            Some(_) => Err(format!("option '{}' does not take a value", name)),
+            None => Ok(()),
+        }
+    };
+
+    let result = match name {
+        "rotate" => viewport.rotated(center, number(value)?),
+        "flip-x" => {
+            no_value()?;
+            viewport.transformed(center, [[-1.0, 0.0], [0.0, 1.0]])
+        }
+        "flip-y" => {
+            no_value()?;
+            viewport.transformed(center, [[1.0, 0.0], [0.0, -1.0]])
+        }
+        "skew" => viewport.transformed(center, [[1.0, number(value)?], [0.0, 1.0]]),
+        "affine" => {
+            let parts: Vec<f64> = value.unwrap_or("").split(',')
+                .map(|p| p.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "option 'affine' needs four numbers A,B,C,D".to_string())?;
+            if parts.len() != 4 {
+                return Err("option 'affine' needs four numbers A,B,C,D".to_string());
+            }
+            viewport.transformed(center, [[parts[0], parts[1]], [parts[2], parts[3]]])
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(result))
+}
+
+#[test]
+fn test_apply_option() {
+    let viewport = Viewport::from_corners((10, 10), Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let center = Complex { re: 0.0, im: 0.0 };
+    assert_eq!(apply_option(viewport, center, "flip-y"),
+               Ok(Some(viewport.transformed(center, [[1.0, 0.0], [0.0, -1.0]]))));
+    assert_eq!(apply_option(viewport, center, "rotate=45"), Ok(Some(viewport.rotated(center, 45.0))));
+    assert_eq!(apply_option(viewport, center, "affine=1,0,0,1"), Ok(Some(viewport)));
+    assert_eq!(apply_option(viewport, center, "limit=5"), Ok(None));
+    assert!(apply_option(viewport, center, "rotate").is_err());
+    assert!(apply_option(viewport, center, "affine=1,2,3").is_err());
+    assert!(apply_option(viewport, center, "flip-x=3").is_err());
+    assert!(apply_option(viewport, center, "flip-y=whatever").is_err());
+    assert!(apply_option(viewport, center, "flip-y=").is_err());
+}
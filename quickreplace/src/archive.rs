@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::{Compression, GzBuilder};
+use zip::write::SimpleFileOptions;
+
+use crate::files;
+
+/*
+Archives and compressed files
+
+Files named .gz, .zip, .tar, .tar.gz or .tgz are opened up instead of being
+passed over as binary, and each member, each file inside, is replaced in as
+if it were a file of its own, named 'archive.zip:dir/file.txt' in diffs,
+search results and the --json report. A .gz file has one member, named as
+the file without the '.gz'. Members that look binary are left alone, as are
+directories, links, and zip members that are encrypted or compressed in a
+way we can't read. Only if a member changes is the archive written again:
+
+- a .gz file is compressed afresh, keeping the name, time and comment in
+  its header;
+- in a zip archive, a changed member is compressed again the way it was
+  before; every other member is copied still compressed, so its bytes stay
+  the same;
+- in a tar file, a changed member gets a copy of its header with the new
+  size, as does any PAX extended header before it that gives the size too;
+  everything else is copied byte for byte.
+
+Archives are read into memory whole, with --stream or without.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Gzip,
+    Zip,
+    Tar,
+    TarGzip,
+}
+
+impl Kind {
+    // The kind of archive 'path' is, going by its name, if it's one at all.
+    pub fn of(path: &Path) -> Option<Kind> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Kind::TarGzip)
+        } else if name.ends_with(".gz") {
+            Some(Kind::Gzip)
+        } else if name.ends_with(".zip") {
+            Some(Kind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Kind::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/*
+Call 'change' with the name and contents of each member of the archive
+'data', read from 'path', that looks like text. 'change' gives the member's
+new contents, or None to leave it as it was.
+
+Returns the archive with the changes made, or None if nothing changed.
+Errors from 'change' are passed on as they are.
+ */
+pub fn rewrite(kind: Kind, path: &Path, data: &[u8],
+               mut change: impl FnMut(&str, &[u8]) -> Result<Option<Vec<u8>>, String>)
+               -> Result<Option<Vec<u8>>, String> {
+    let broken = |e: &dyn std::fmt::Display| format!("failed to read archive '{}': {}", path.display(), e);
+    let compress = |header: GzBuilder, contents: Vec<u8>| gzip(header, &contents)
+        .map_err(|e| format!("failed to compress '{}': {:?}", path.display(), e));
+    match kind {
+        Kind::Gzip => {
+            let (header, contents) = gunzip(data).map_err(|e| broken(&e))?;
+            let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            let name = name.get(..name.len().saturating_sub(3)).unwrap_or_default();
+            if files::looks_binary(&contents) {
+                return Ok(None);
+            }
+            change(name, &contents)?.map(|new| compress(header, new)).transpose()
+        }
+        Kind::Tar => rewrite_tar(data, &mut change, broken),
+        Kind::TarGzip => {
+            let (header, tar) = gunzip(data).map_err(|e| broken(&e))?;
+            rewrite_tar(&tar, &mut change, broken)?.map(|new| compress(header, new)).transpose()
+        }
+        Kind::Zip => rewrite_zip(data, &mut change, broken),
+    }
+}
+
+// The contents of a gzip file, and a header like the one it had for compressing it again.
+fn gunzip(data: &[u8]) -> io::Result<(GzBuilder, Vec<u8>)> {
+    let mut decoder = MultiGzDecoder::new(data);
+    let mut contents = Vec::new();
+    decoder.read_to_end(&mut contents)?;
+    let mut builder = GzBuilder::new();
+    if let Some(header) = decoder.header() {
+        builder = builder.mtime(header.mtime()).operating_system(header.operating_system());
+        if let Some(name) = header.filename() {
+            builder = builder.filename(name);
+        }
+        if let Some(comment) = header.comment() {
+            builder = builder.comment(comment);
+        }
+    }
+    Ok((builder, contents))
+}
+
+fn gzip(header: GzBuilder, contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = header.write(Vec::new(), Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/*
+The tar file is gone through entry by entry, GNU long names and PAX
+extended headers included, so that the bytes between the changed members
+can be copied as they are. A PAX header can give a member's name and size
+in place of its own header; a size that differs from the header's, as for
+a member over 8 GiB, isn't supported.
+ */
+fn rewrite_tar(data: &[u8], change: &mut impl FnMut(&str, &[u8]) -> Result<Option<Vec<u8>>, String>,
+               broken: impl Fn(&dyn std::fmt::Display) -> String) -> Result<Option<Vec<u8>>, String> {
+    // Everything in a tar file comes in 512-byte blocks.
+    let padded = |size: usize| size.div_ceil(512) * 512;
+
+    let mut archive = tar::Archive::new(data);
+    let mut out = Vec::new();
+    let mut copied = 0;
+    let mut long_name = None;
+    // The PAX extended header for the next member: where it starts, and the header and records.
+    let mut pax = None;
+    for entry in archive.entries().map_err(|e| broken(&e))?.raw(true) {
+        let entry = entry.map_err(|e| broken(&e))?;
+        let (header_at, start) = (entry.raw_header_position() as usize, entry.raw_file_position() as usize);
+        let size = entry.header().entry_size().map_err(|e| broken(&e))? as usize;
+        let contents = data.get(start..start + size).ok_or_else(|| broken(&"it ends in the middle of a member"))?;
+        let kind = entry.header().entry_type();
+        if kind.is_gnu_longname() {
+            long_name = Some(contents.strip_suffix(b"\0").unwrap_or(contents).to_vec());
+            continue;
+        }
+        if kind.is_pax_local_extensions() {
+            pax = Some((header_at, entry.header().clone(), contents));
+            continue;
+        }
+        let pax = pax.take();
+        let records = pax.as_ref().map_or(Ok(Vec::new()), |(_, _, records)| tar::PaxExtensions::new(records).collect())
+            .map_err(|e| broken(&e))?;
+        let record = |key: &[u8]| records.iter().find(|record| record.key_bytes() == key).map(|r| r.value_bytes());
+        if record(b"size").is_some_and(|value| value != size.to_string().as_bytes()) {
+            return Err(broken(&"a PAX header gives a member's size as other than its own header does"));
+        }
+        let name = record(b"path").map(<[u8]>::to_vec).or(long_name.take())
+            .unwrap_or_else(|| entry.path_bytes().into_owned());
+        if !kind.is_file() || files::looks_binary(contents) {
+            continue;
+        }
+        if let Some(new) = change(&String::from_utf8_lossy(&name), contents)? {
+            if let Some((pax_at, mut pax_header, old)) = pax {
+                let size = new.len().to_string();
+                let records: Vec<u8> = records.iter()
+                    .flat_map(|record| match record.key_bytes() {
+                        b"size" => pax_record(b"size", size.as_bytes()),
+                        key => pax_record(key, record.value_bytes()),
+                    })
+                    .collect();
+                pax_header.set_size(records.len() as u64);
+                pax_header.set_cksum();
+                out.extend_from_slice(&data[copied..pax_at]);
+                out.extend_from_slice(pax_header.as_bytes());
+                out.extend_from_slice(&records);
+                out.resize(out.len() + padded(records.len()) - records.len(), 0);
+                copied = pax_at + 512 + padded(old.len());
+            }
+            let mut header = entry.header().clone();
+            header.set_size(new.len() as u64);
+            header.set_cksum();
+            out.extend_from_slice(&data[copied..header_at]);
+            out.extend_from_slice(header.as_bytes());
+            out.extend_from_slice(&new);
+            out.resize(out.len() + padded(new.len()) - new.len(), 0);
+            copied = start + padded(size);
+        }
+    }
+    if copied == 0 {
+        return Ok(None);
+    }
+    out.extend_from_slice(&data[copied.min(data.len())..]);
+    Ok(Some(out))
+}
+
+// A PAX record, "<length> <key>=<value>\n", where the length counts its own digits.
+fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+    [length.to_string().as_bytes(), b" ", key, b"=", value, b"\n"].concat()
+}
+
+/*
+The zip archive is gone through twice: once to see what changes, so an
+archive nothing matched in isn't rebuilt at all, and once to build it.
+ */
+fn rewrite_zip(data: &[u8], change: &mut impl FnMut(&str, &[u8]) -> Result<Option<Vec<u8>>, String>,
+               broken: impl Fn(&dyn std::fmt::Display) -> String) -> Result<Option<Vec<u8>>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| broken(&e))?;
+    let mut changes = HashMap::new();
+    for i in 0..archive.len() {
+        // Encrypted, or compressed some way we can't undo.
+        let Ok(mut file) = archive.by_index(i) else { continue };
+        if file.is_dir() || file.is_symlink() {
+            continue;
+        }
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(|e| broken(&e))?;
+        if files::looks_binary(&contents) {
+            continue;
+        }
+        if let Some(new) = change(file.name(), &contents)? {
+            changes.insert(i, new);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(None);
+    }
+
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i).map_err(|e| broken(&e))?;
+        let new = match changes.remove(&i) {
+            Some(new) => new,
+            None => {
+                writer.raw_copy_file(file).map_err(|e| broken(&e))?;
+                continue;
+            }
+        };
+        let mut options = SimpleFileOptions::default()
+            .compression_method(file.compression())
+            .large_file(new.len() as u64 >= u32::MAX as u64);
+        if let Some(modified) = file.last_modified() {
+            options = options.last_modified_time(modified);
+        }
+        if let Some(mode) = file.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+        let name = file.name().to_string();
+        drop(file);
+        writer.start_file(name, options).map_err(|e| broken(&e))?;
+        writer.write_all(&new).map_err(|e| broken(&e))?;
+    }
+    writer.set_raw_comment(archive.comment().into());
+    Ok(Some(writer.finish().map_err(|e| broken(&e))?.into_inner()))
+}
+
+#[test]
+fn test_rewrite() {
+    let upper = |name: &str, contents: &[u8]| Ok((name != "skip.txt" && contents.starts_with(b"a"))
+        .then(|| contents.to_ascii_uppercase()));
+    let members = |kind, path: &str, data: &[u8]| {
+        let mut found = Vec::new();
+        rewrite(kind, Path::new(path), data, |name, contents| {
+            found.push((name.to_string(), contents.to_vec()));
+            Ok(None)
+        }).unwrap();
+        found
+    };
+
+    // A tar file with a text member to change, one to leave, a binary one and a directory.
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, contents) in [("a.txt", &b"abc\n"[..]), ("skip.txt", b"abc"), ("b.bin", b"\0\0\0\x01abc"), ("d/", b"")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_entry_type(if name.ends_with('/') { tar::EntryType::Directory } else { tar::EntryType::Regular });
+        header.set_mode(0o644);
+        builder.append_data(&mut header, name, contents).unwrap();
+    }
+    let tar = builder.into_inner().unwrap();
+    let new = rewrite(Kind::Tar, Path::new("t.tar"), &tar, upper).unwrap().unwrap();
+    assert_eq!(new.len(), tar.len());
+    assert_eq!(members(Kind::Tar, "t.tar", &new), vec![("a.txt".to_string(), b"ABC\n".to_vec()),
+                                               ("skip.txt".to_string(), b"abc".to_vec())]);
+    assert_eq!(new[1024..], tar[1024..]);
+    assert_eq!(rewrite(Kind::Tar, Path::new("t.tar"), &tar, |_, _| Ok(None)), Ok(None));
+
+    // PAX extended headers give a member's long name and size, which has to change with it.
+    let name = format!("{}/a.txt", "d".repeat(120));
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_pax_extensions([("path", name.as_bytes()), ("size", b"4"), ("comment", b"x")]).unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_size(4);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "short.txt", &b"abc\n"[..]).unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_size(3);
+    builder.append_data(&mut header, "b.txt", &b"bcd"[..]).unwrap();
+    let pax_tar = builder.into_inner().unwrap();
+    let longer = |_: &str, contents: &[u8]| Ok(contents.starts_with(b"a").then(|| b"abc".repeat(200)));
+    let new = rewrite(Kind::Tar, Path::new("t.tar"), &pax_tar, longer).unwrap().unwrap();
+    let mut archive = tar::Archive::new(&new[..]);
+    let read: Vec<(String, Vec<u8>)> = archive.entries().unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            (entry.path().unwrap().display().to_string(), contents)
+        })
+        .collect();
+    assert_eq!(read, vec![(name.clone(), b"abc".repeat(200)), ("b.txt".to_string(), b"bcd".to_vec())]);
+    assert_eq!(members(Kind::Tar, "t.tar", &new)[0].0, name);
+    assert_eq!(pax_record(b"size", b"600"), b"12 size=600\n");
+    assert_eq!(pax_record(b"k", b"12345"), b"11 k=12345\n");
+
+    let tgz = gzip(GzBuilder::new().filename("t.tar"), &tar).unwrap();
+    let new = rewrite(Kind::TarGzip, Path::new("t.tgz"), &tgz, upper).unwrap().unwrap();
+    assert_eq!(members(Kind::Tar, "t.tar", &gunzip(&new).unwrap().1)[0].1, b"ABC\n");
+
+    let gz = gzip(GzBuilder::new().filename("a.txt").mtime(7), b"abc").unwrap();
+    let new = rewrite(Kind::Gzip, Path::new("a.txt.gz"), &gz, upper).unwrap().unwrap();
+    assert_eq!(members(Kind::Gzip, "a.txt.gz", &new), vec![("a.txt".to_string(), b"ABC".to_vec())]);
+    let mut decoder = MultiGzDecoder::new(&new[..]);
+    io::copy(&mut decoder, &mut io::sink()).unwrap();
+    assert_eq!((decoder.header().unwrap().filename(), decoder.header().unwrap().mtime()), (Some(&b"a.txt"[..]), 7));
+
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    writer.start_file("a.txt", stored).unwrap();
+    writer.write_all(b"abc").unwrap();
+    writer.start_file("b.txt", SimpleFileOptions::default()).unwrap();
+    writer.write_all(b"bcd").unwrap();
+    writer.set_comment("fixtures");
+    let zipped = writer.finish().unwrap().into_inner();
+    let new = rewrite(Kind::Zip, Path::new("t.zip"), &zipped, upper).unwrap().unwrap();
+    assert_eq!(members(Kind::Zip, "t.zip", &new), vec![("a.txt".to_string(), b"ABC".to_vec()),
+                                               ("b.txt".to_string(), b"bcd".to_vec())]);
+    let mut archive = zip::ZipArchive::new(Cursor::new(&new[..])).unwrap();
+    assert_eq!(archive.comment(), b"fixtures");
+    assert_eq!(archive.by_index(0).unwrap().compression(), zip::CompressionMethod::Stored);
+
+    assert!(rewrite(Kind::Zip, Path::new("t.zip"), b"not a zip", upper).unwrap_err().starts_with("failed to read"));
+    assert_eq!(Kind::of(Path::new("x/Fixtures.TAR.GZ")), Some(Kind::TarGzip));
+    assert_eq!(Kind::of(Path::new("a.txt")), None);
+}
@@ -0,0 +1,81 @@
+use similar::{ChangeTag, TextDiff};
+use text_colorizer::*;
+
+/*
+A unified diff between the original and replaced text of one file, the way
+'diff -u' or 'git diff' would show it: removed lines in red, added lines in
+green, hunk headers in cyan, with three lines of context around each change.
+ */
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut out = header(path);
+
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        out += &format!("{}\n", hunk.header().to_string().cyan());
+        for change in hunk.iter_changes() {
+            let line = change.value().trim_end_matches(['\n', '\r']);
+            let line = match change.tag() {
+                ChangeTag::Delete => format!("-{}", line).red().to_string(),
+                ChangeTag::Insert => format!("+{}", line).green().to_string(),
+                ChangeTag::Equal => format!(" {}", line),
+            };
+            out += &line;
+            out += "\n";
+            if change.missing_newline() {
+                out += "\\ No newline at end of file\n";
+            }
+        }
+    }
+    out
+}
+
+// The '---' and '+++' lines that start the diff of one file.
+pub fn header(path: &str) -> String {
+    format!("{}\n{}\n", format!("--- a/{}", path).bold(), format!("+++ b/{}", path).bold())
+}
+
+/*
+A hunk for one changed line and no context, for --stream, which never holds
+more than a line of the file and so can't show what's around it.
+ */
+pub fn line_hunk(number: usize, old: &str, new: &str) -> String {
+    format!("{}\n{}\n{}\n", format!("@@ -{} +{} @@", number, number).cyan(),
+            format!("-{}", old).red(), format!("+{}", new).green())
+}
+
+// Drop the terminal color codes from 'text', so tests can compare plain strings.
+#[cfg(test)]
+pub fn strip_colors(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c == 'm' {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[test]
+fn test_unified_diff() {
+    let unified_diff = |path, old, new| strip_colors(&unified_diff(path, old, new));
+    let old = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+    let new = "one\n2\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+    assert_eq!(unified_diff("f.txt", old, new),
+               "--- a/f.txt\n+++ b/f.txt\n@@ -1,5 +1,5 @@\n one\n-two\n+2\n three\n four\n five\n");
+
+    assert_eq!(unified_diff("f.txt", "a", "b"),
+               "--- a/f.txt\n+++ b/f.txt\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n\\ No newline at end of file\n");
+}
+
+#[test]
+fn test_line_hunk() {
+    assert_eq!(strip_colors(&format!("{}{}", header("f.txt"), line_hunk(12, "a", "b"))),
+               "--- a/f.txt\n+++ b/f.txt\n@@ -12 +12 @@\n-a\n+b\n");
+}
@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+use crate::files;
+
+/*
+Text encodings
+
+Files needn't be UTF-8. Each one is decoded for matching and the result
+encoded back the same way, so a Latin-1 file stays Latin-1. Unless
+--encoding names one, the encoding is worked out from the file itself:
+
+- a byte order mark says UTF-8, UTF-16LE or UTF-16BE;
+- text with a NUL in one half of most byte pairs and hardly any in the
+  other is UTF-16 without a byte order mark, mostly ASCII;
+- text that is valid UTF-8 is UTF-8;
+- anything else is Latin-1, read as windows-1252 as browsers do, which
+  gives every byte a character and so always decodes.
+
+That last would make text of any file, so one that looks binary, with a NUL
+or many control characters and not UTF-16, is refused unless --encoding
+says what it's in; see 'files::looks_binary'. --bytes edits it as it is.
+
+--encoding takes any of the labels in the WHATWG Encoding Standard, e.g.
+'latin1', 'utf-16le', 'shift_jis' or 'gb18030'. A byte order mark is kept as
+U+FEFF at the start of the text, which 'endings' sets aside and encoding
+writes back. A replacement with a character the encoding has no way to
+write is an error, and the file is left alone.
+
+Matching happens on the decoded text, so for a file that isn't UTF-8 the
+offsets of the matches are taken back to where they are in the file, for
+the --json report; see 'encoded_offsets'.
+ */
+pub fn lookup(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label_no_replacement(label.as_bytes()).ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+// The encoding 'data' looks to be in.
+pub fn detect(data: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(data) {
+        return encoding;
+    }
+    if let Some(encoding) = guess_utf16(data) {
+        return encoding;
+    }
+    if std::str::from_utf8(data).is_ok() { UTF_8 } else { WINDOWS_1252 }
+}
+
+// Whether 'data' looks like UTF-16, whose NULs don't make it binary.
+pub fn is_utf16(data: &[u8]) -> bool {
+    let encoding = Encoding::for_bom(data).map(|(encoding, _)| encoding).or_else(|| guess_utf16(data));
+    encoding == Some(UTF_16LE) || encoding == Some(UTF_16BE)
+}
+
+// UTF-16 without a byte order mark, going by where the NULs are in the first few KB.
+fn guess_utf16(data: &[u8]) -> Option<&'static Encoding> {
+    let sample = &data[..data.len().min(4096) & !1];
+    let pairs = sample.len() / 2;
+    let even = sample.iter().step_by(2).filter(|&&byte| byte == 0).count();
+    let odd = sample.iter().skip(1).step_by(2).filter(|&&byte| byte == 0).count();
+    match (even, odd) {
+        _ if pairs == 0 => None,
+        (even, odd) if odd * 3 >= pairs && even * 10 <= odd => Some(UTF_16LE),
+        (even, odd) if even * 3 >= pairs && odd * 10 <= even => Some(UTF_16BE),
+        _ => None,
+    }
+}
+
+// 'data' as text, in 'forced' or else the encoding it looks to be in, and that encoding.
+pub fn decode<'d>(data: &'d [u8],
+                  forced: Option<&'static Encoding>) -> Result<(Cow<'d, str>, &'static Encoding), String> {
+    let encoding = match forced {
+        Some(encoding) => encoding,
+        None if files::looks_binary(data) => {
+            return Err("it looks like a binary file (use --bytes or --encoding to edit it anyway)".to_string());
+        }
+        None => detect(data),
+    };
+    let text = if encoding == UTF_8 {
+        std::str::from_utf8(data).map(Cow::Borrowed).map_err(|e| format!("not valid UTF-8: {}", e))?
+    } else {
+        encoding.decode_without_bom_handling_and_without_replacement(data)
+            .ok_or_else(|| format!("not valid {}", encoding.name()))?
+    };
+    Ok((text, encoding))
+}
+
+/*
+'text' in 'encoding'. The encoding_rs encoders write UTF-16 as UTF-8, as the
+web does for forms, so UTF-16 is done here.
+ */
+pub fn encode<'t>(text: &'t str, encoding: &'static Encoding) -> Result<Cow<'t, [u8]>, String> {
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let unit = if encoding == UTF_16LE { u16::to_le_bytes } else { u16::to_be_bytes };
+        return Ok(Cow::Owned(text.encode_utf16().flat_map(unit).collect()));
+    }
+    let (bytes, _, unmappable) = encoding.encode(text);
+    if !unmappable {
+        return Ok(bytes);
+    }
+    let mut buffer = [0; 4];
+    let missing = text.chars().find(|c| encoding.encode(c.encode_utf8(&mut buffer)).2);
+    Err(format!("{} has no way to write {:?}", encoding.name(), missing.unwrap_or(char::REPLACEMENT_CHARACTER)))
+}
+
+/*
+Where each of the 'offsets' into 'text', which are in order, falls once
+'text' is in 'encoding'. Each stretch between them is encoded on its own,
+which comes to the same as encoding it all for every encoding but
+ISO-2022-JP, whose escapes depend on what came before.
+ */
+pub fn encoded_offsets(text: &str, encoding: &'static Encoding, offsets: &[usize]) -> Vec<usize> {
+    if encoding == UTF_8 {
+        return offsets.to_vec();
+    }
+    let (mut from, mut encoded) = (0, 0);
+    offsets.iter().map(|&offset| {
+        let stretch = &text[from..offset];
+        encoded += encode(stretch, encoding).map_or(stretch.len(), |bytes| bytes.len());
+        from = offset;
+        encoded
+    }).collect()
+}
+
+#[test]
+fn test_encoding() {
+    let latin1 = b"caf\xe9 \x80\n";
+    assert_eq!(detect(latin1), WINDOWS_1252);
+    let (text, encoding) = decode(latin1, None).unwrap();
+    assert_eq!(text, "café €\n");
+    assert_eq!(encode(&text, encoding).unwrap(), &latin1[..]);
+    assert_eq!(encode("日本", encoding).unwrap_err(), "windows-1252 has no way to write '日'");
+
+    let utf16: Vec<u8> = "\u{feff}hé\n".encode_utf16().flat_map(u16::to_be_bytes).collect();
+    assert_eq!(detect(&utf16), UTF_16BE);
+    let (text, encoding) = decode(&utf16, None).unwrap();
+    assert_eq!(text, "\u{feff}hé\n");
+    assert_eq!(encode(&text, encoding).unwrap(), utf16);
+
+    let bare: Vec<u8> = "plain text".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    assert_eq!(detect(&bare), UTF_16LE);
+    assert!(is_utf16(&bare) && !is_utf16(b"a\0\0\0b"));
+    assert_eq!(detect("naïve".as_bytes()), UTF_8);
+    assert_eq!(detect(b""), UTF_8);
+
+    // Forced, the file must be what it's said to be.
+    assert_eq!(decode(b"caf\xc3\xa9", Some(lookup("latin1").unwrap())).unwrap().0, "cafÃ©");
+    assert!(decode(b"caf\xe9", Some(UTF_8)).is_err());
+    assert!(decode(b"odd", Some(UTF_16LE)).is_err());
+    assert!(lookup("klingon").is_err());
+
+    // Binary files aren't taken for Latin-1, but can still be said to be.
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x01";
+    assert!(decode(png, None).unwrap_err().contains("binary"));
+    assert!(decode(b"\x01\x02\x03ab\x04\x05", None).is_err());
+    assert!(decode(png, Some(WINDOWS_1252)).is_ok());
+
+    assert_eq!(encoded_offsets("\u{feff}é €x", UTF_16LE, &[0, 3, 5, 6, 9]), vec![0, 2, 4, 6, 8]);
+    assert_eq!(encoded_offsets("é €x", WINDOWS_1252, &[2, 6, 7]), vec![1, 3, 4]);
+}
@@ -0,0 +1,77 @@
+/*
+Line endings and byte order marks
+
+Rewriting a file shouldn't change how it's laid out. A UTF-8 byte order mark
+is set aside before matching, so that '^' and the file's first word match as
+they would without it, and is put back in front of what's written. And when
+every line of a file ends '\r\n', the line breaks a replacement brings in as
+a bare '\n' become '\r\n' too, rather than leaving the file with a mix of
+both. If the replacements take out every '\r\n', as when converting a file
+to '\n' endings, the result is left as it is.
+ */
+pub const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// 'data' split into its byte order mark, or nothing, and the rest.
+pub fn split_bom(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.strip_prefix(BOM) {
+        Some(rest) => (BOM, rest),
+        None => (&[], data),
+    }
+}
+
+// Whether 'data' has line breaks and they're all '\r\n'.
+pub fn is_crlf(data: &[u8]) -> bool {
+    let mut breaks = data.iter().enumerate().filter(|&(_, &byte)| byte == b'\n').peekable();
+    breaks.peek().is_some() && breaks.all(|(i, _)| i > 0 && data[i - 1] == b'\r')
+}
+
+// Whether 'data' has a '\n' without a '\r' before it.
+fn has_bare_lf(data: &[u8]) -> bool {
+    data.iter().enumerate().any(|(i, &byte)| byte == b'\n' && (i == 0 || data[i - 1] != b'\r'))
+}
+
+// 'data' with every bare '\n' made '\r\n'.
+pub fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(byte);
+    }
+    out
+}
+
+// 'replaced', with the line endings 'original' had throughout if the replacements mixed them.
+pub fn keep_line_endings(original: &[u8], replaced: Vec<u8>) -> Vec<u8> {
+    if is_crlf(original) && has_bare_lf(&replaced) && replaced.windows(2).any(|pair| pair == b"\r\n") {
+        to_crlf(&replaced)
+    } else {
+        replaced
+    }
+}
+
+// The same, for text: only ever adding '\r's keeps it UTF-8.
+pub fn keep_line_endings_in(original: &str, replaced: String) -> String {
+    String::from_utf8(keep_line_endings(original.as_bytes(), replaced.into_bytes()))
+        .expect("adding '\\r' keeps text UTF-8")
+}
+
+#[test]
+fn test_endings() {
+    assert_eq!(split_bom(b"\xEF\xBB\xBFabc"), (BOM, &b"abc"[..]));
+    assert_eq!(split_bom(b"abc"), (&b""[..], &b"abc"[..]));
+
+    assert!(is_crlf(b"a\r\nb\r\n"));
+    assert!(is_crlf(b"a\r\nb"));
+    assert!(!is_crlf(b"a\r\nb\n"));
+    assert!(!is_crlf(b"\n"));
+    assert!(!is_crlf(b"no breaks"));
+
+    assert_eq!(to_crlf(b"\na\nb\r\nc"), b"\r\na\r\nb\r\nc");
+    assert_eq!(keep_line_endings(b"x\r\ny\r\n", b"a\nb\r\ny\r\n".to_vec()), b"a\r\nb\r\ny\r\n");
+    // Every '\r\n' replaced away: converting on purpose.
+    assert_eq!(keep_line_endings(b"x\r\ny\r\n", b"x\ny\n".to_vec()), b"x\ny\n");
+    assert_eq!(keep_line_endings(b"x\ny\n", b"a\nb\r\n".to_vec()), b"a\nb\r\n");
+    assert_eq!(keep_line_endings_in("é\r\n", "é\nb\r\n".to_string()), "é\r\nb\r\n");
+}
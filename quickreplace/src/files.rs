@@ -0,0 +1,467 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ignore::overrides::OverrideBuilder;
+use ignore::types::TypesBuilder;
+use ignore::WalkBuilder;
+use text_colorizer::*;
+
+use crate::archive;
+use crate::encoding;
+
+/*
+Turn the <FILE|GLOB> arguments into a list of files.
+
+Arguments containing glob metacharacters ('*', '?' or '[') are expanded with
+the 'glob' crate, so patterns work even where the shell doesn't expand them
+(or when they are quoted to get '**' recursion). Anything else is taken as a
+file name as-is. A file named twice is only returned once.
+
+Returns the files found and a message for each argument that went wrong.
+ */
+pub fn expand_inputs(inputs: &[String]) -> (Vec<PathBuf>, Vec<String>) {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut errors = Vec::new();
+
+    for input in inputs {
+        if !is_glob(input) {
+            paths.push(PathBuf::from(input));
+            continue;
+        }
+
+        let matches = match glob::glob(input) {
+            Ok(matches) => matches,
+            Err(e) => {
+                errors.push(format!("invalid glob pattern '{}': {}", input, e));
+                continue;
+            }
+        };
+        let before = paths.len();
+        for entry in matches {
+            match entry {
+                Ok(path) if path.is_file() => paths.push(path),
+                Ok(_) => {} // directories and the like
+                Err(e) => errors.push(format!("failed to read '{}': {}", e.path().display(), e.error())),
+            }
+        }
+        if paths.len() == before {
+            errors.push(format!("no files match '{}'", input));
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    paths.retain(|p| seen.insert(p.clone()));
+    (paths, errors)
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+// How --recursive chooses files, set from the command line.
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    pub hidden: bool,
+    pub no_ignore: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub types: Vec<String>,
+}
+
+/*
+Find every file under the 'roots' that --recursive should rewrite.
+
+The 'ignore' crate does the walking, so .gitignore, .ignore and global git
+excludes are honoured and hidden files are skipped just as 'git' and 'rg'
+would, unless WalkOptions says otherwise. --include and --exclude globs are
+gitignore-style: a pattern without a '/' matches the file name anywhere,
+and one with a '/' matches paths relative to the current directory. Files
+that look binary are left out, except for archives; see 'archive'.
+
+Returns the files found, sorted, and a message for everything that couldn't
+be read.
+ */
+pub fn walk(roots: &[String], options: &WalkOptions) -> (Vec<PathBuf>, Vec<String>) {
+    let mut errors = Vec::new();
+    let (first, rest) = match roots.split_first() {
+        Some(split) => split,
+        None => return (Vec::new(), errors),
+    };
+
+    let mut builder = WalkBuilder::new(first);
+    for root in rest {
+        builder.add(root);
+    }
+    builder.hidden(!options.hidden)
+        .ignore(!options.no_ignore)
+        .git_ignore(!options.no_ignore)
+        .git_global(!options.no_ignore)
+        .git_exclude(!options.no_ignore)
+        .parents(!options.no_ignore)
+        // Honour .gitignore files even outside a git checkout.
+        .require_git(false);
+
+    match filters(options) {
+        Ok((overrides, types)) => {
+            builder.overrides(overrides).types(types);
+        }
+        Err(e) => return (Vec::new(), vec![e]),
+    }
+
+    let mut paths = Vec::new();
+    for entry in builder.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                errors.push(e.to_string());
+                continue;
+            }
+        };
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        if archive::Kind::of(entry.path()).is_some() {
+            paths.push(entry.into_path());
+            continue;
+        }
+        match is_binary(entry.path()) {
+            Ok(false) => paths.push(entry.into_path()),
+            Ok(true) => {}
+            Err(e) => errors.push(format!("failed to read from file '{}': {}", entry.path().display(), e)),
+        }
+    }
+
+    paths.sort();
+    paths.dedup();
+    (paths, errors)
+}
+
+// Build the --include/--exclude overrides and the --type matcher.
+fn filters(options: &WalkOptions) -> Result<(ignore::overrides::Override, ignore::types::Types), String> {
+    let mut overrides = OverrideBuilder::new(".");
+    for glob in &options.include {
+        overrides.add(glob).map_err(|e| format!("invalid --include glob '{}': {}", glob, e))?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{}", glob)).map_err(|e| format!("invalid --exclude glob '{}': {}", glob, e))?;
+    }
+    let overrides = overrides.build().map_err(|e| e.to_string())?;
+
+    let mut types = TypesBuilder::new();
+    types.add_defaults();
+    for name in &options.types {
+        types.select(name);
+    }
+    let types = types.build().map_err(|e| format!("invalid --type: {}", e))?;
+
+    Ok((overrides, types))
+}
+
+// Guess whether the file at 'path' is binary, from its start; see 'looks_binary'.
+pub fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut buffer = [0; 8192];
+    let mut file = File::open(path)?;
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(looks_binary(&buffer[..filled]))
+}
+
+/*
+Guess whether 'data' is binary much as git and grep do: text files
+practically never contain a NUL byte, or more than the odd control
+character, so look near the start. UTF-16 text is the exception, being
+full of NULs; see 'encoding'.
+ */
+pub fn looks_binary(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(8192)];
+    let control = sample.iter().filter(|&&byte| (byte < 0x20 && !b"\t\n\r\x0c\x1b".contains(&byte)) || byte == 0x7f)
+        .count();
+    (sample.contains(&0) || control * 10 > sample.len()) && !encoding::is_utf16(sample)
+}
+
+// As with most Unix tools, '-' as the input or output file means stdin or stdout.
+pub const STDIO: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+// The contents of the input file 'path', which may be stdin.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    if !is_stdio(path) {
+        return fs::read(path);
+    }
+    let mut data = Vec::new();
+    io::stdin().lock().read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+// The output file 'name', which may be stdout, created or emptied.
+pub fn create(name: &str) -> io::Result<Box<dyn Write>> {
+    if name == STDIO {
+        Ok(Box::new(BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(name)?)))
+    }
+}
+
+/*
+Write 'data' to the output file 'name', which may be stdout. 'write_all'
+carries on after a partial write until everything is out.
+ */
+pub fn write_output(name: &str, data: &[u8]) -> io::Result<()> {
+    let mut output = create(name)?;
+    output.write_all(data)?;
+    output.flush()
+}
+
+/*
+Rust ignores SIGPIPE, so when whoever reads our stdout goes away, as 'head'
+does once it has enough lines, writes fail with BrokenPipe instead of
+killing the process. That's no failure of ours: there's just no one left
+to write for.
+ */
+pub fn ignore_broken_pipe(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/*
+Standard output for all we print there: diffs, counts, matches, reports.
+When the reader goes away the first write to fail says so, as with
+'ignore_broken_pipe', and everything after it is quietly dropped, so the
+run still finishes and its exit status still says how it went.
+ */
+pub struct Printer {
+    out: Box<dyn Write>,
+    closed: bool,
+}
+
+impl Printer {
+    pub fn stdout() -> Printer {
+        Printer::new(Box::new(io::stdout()))
+    }
+
+    pub fn new(out: Box<dyn Write>) -> Printer {
+        Printer { out, closed: false }
+    }
+
+    pub fn print(&mut self, text: &str) {
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.out.write_all(text.as_bytes()).and_then(|()| self.out.flush()) {
+            self.closed = true;
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("{} failed to write to stdout: {:?}", "Error:".red().bold(), e);
+            }
+        }
+    }
+}
+
+// How an in-place rewrite treats the original, set from the command line.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RewriteOptions {
+    // Copy the original to 'path.bak' first.
+    pub backup: bool,
+    // Give the new file the original's access and modification times.
+    pub keep_mtime: bool,
+}
+
+/*
+Replace the contents of 'path' with 'data' without ever leaving a half-written
+file behind: the data goes to a temporary file next to the original, which is
+then renamed over it. Renaming within one directory is atomic, so readers see
+either the old file or the new one.
+
+The temporary file gets the original's permissions, setuid and sticky bits
+included, and on Unix its owner and group too, where we're allowed to set
+them; otherwise the rewritten file is ours. Its times are those of the
+rewrite unless 'options' says to keep the original's.
+
+A symlink is followed, and the file it points to rewritten, backup and all:
+renaming over the link would put a file of its own in its place and leave
+the real one as it was.
+ */
+pub fn write_atomically(path: &Path, data: &[u8], options: RewriteOptions) -> io::Result<()> {
+    rewrite_atomically(path, options, |file| file.write_all(data).map(|()| true))
+}
+
+/*
+Like 'write_atomically', but 'write' produces the new contents bit by bit,
+so they never need to be in memory all at once. 'write' may read 'path'
+while it works. If it returns false the temporary file is thrown away and
+the original is left as it was.
+ */
+pub fn rewrite_atomically(path: &Path, options: RewriteOptions,
+                          write: impl FnOnce(&mut BufWriter<File>) -> io::Result<bool>) -> io::Result<()> {
+    let path = fs::canonicalize(path)?;
+    let temp = temp_path(&path);
+    let result = write_and_rename(&path, &temp, options, write);
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map(|_| ())
+}
+
+fn write_and_rename(path: &Path, temp: &Path, options: RewriteOptions,
+                    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<bool>) -> io::Result<bool> {
+    let metadata = fs::metadata(path)?;
+
+    let mut file = BufWriter::new(File::create(temp)?);
+    if !write(&mut file)? {
+        return Ok(false);
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    // Before the permissions, since changing the owner clears the setuid bit.
+    keep_owner(&file, &metadata);
+    file.set_permissions(metadata.permissions())?;
+    if options.keep_mtime {
+        file.set_times(fs::FileTimes::new().set_accessed(metadata.accessed()?).set_modified(metadata.modified()?))?;
+    }
+    file.sync_all()?;
+    drop(file);
+
+    if options.backup {
+        fs::copy(path, backup_path(path))?;
+    }
+    fs::rename(temp, path)?;
+    Ok(true)
+}
+
+/*
+Only root can give a file away, so for anyone else this fails unless the
+original was theirs already. Like 'sed -i', carry on regardless.
+ */
+#[cfg(unix)]
+fn keep_owner(file: &File, metadata: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    let _ = std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_file: &File, _metadata: &fs::Metadata) {}
+
+// A hidden sibling of 'path' that no other quickreplace process will pick.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.quickreplace-{}.tmp", name, std::process::id()))
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+#[test]
+fn test_write_atomically() {
+    let dir = std::env::temp_dir().join(format!("quickreplace-files-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.txt");
+    fs::write(&path, "old").unwrap();
+
+    let backup = RewriteOptions { backup: true, ..Default::default() };
+    write_atomically(&path, b"new", backup).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert_eq!(fs::read_to_string(dir.join("a.txt.bak")).unwrap(), "old");
+    assert!(!temp_path(&path).exists());
+
+    rewrite_atomically(&path, RewriteOptions::default(), |file| file.write_all(b"unused").map(|()| false)).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert!(!temp_path(&path).exists());
+
+    let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+    write_atomically(&path, b"newer", RewriteOptions { keep_mtime: true, ..Default::default() }).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), old);
+    write_atomically(&path, b"newest", RewriteOptions::default()).unwrap();
+    assert!(fs::metadata(&path).unwrap().modified().unwrap() > old);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o754)).unwrap();
+        write_atomically(&path, b"x", RewriteOptions::default()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o754);
+
+        let link = dir.join("link.txt");
+        std::os::unix::fs::symlink("a.txt", &link).unwrap();
+        write_atomically(&link, b"linked", RewriteOptions::default()).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "linked");
+        fs::remove_file(&link).unwrap();
+    }
+
+    assert!(write_atomically(&dir.join("missing.txt"), b"x", RewriteOptions::default()).is_err());
+    assert!(!temp_path(&dir.join("missing.txt")).exists());
+
+    fs::write(dir.join("b.txt"), "").unwrap();
+    let pattern = dir.join("*.txt").to_string_lossy().into_owned();
+    let (paths, errors) = expand_inputs(&[pattern.clone(), path.to_string_lossy().into_owned()]);
+    assert_eq!(paths, vec![path.clone(), dir.join("b.txt")]);
+    assert!(errors.is_empty());
+
+    let (_, errors) = expand_inputs(&[dir.join("*.none").to_string_lossy().into_owned()]);
+    assert_eq!(errors.len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_walk() {
+    let dir = std::env::temp_dir().join(format!("quickreplace-walk-{}", std::process::id()));
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::create_dir_all(dir.join(".hidden")).unwrap();
+    fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+    fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(dir.join("src/notes.txt"), "notes").unwrap();
+    fs::write(dir.join("src/image.png"), b"\x89PNG\r\n\x1a\n\0\0").unwrap();
+    fs::write(dir.join("target/out.rs"), "built").unwrap();
+    fs::write(dir.join(".hidden/secret.rs"), "shh").unwrap();
+
+    let root = vec![dir.to_string_lossy().into_owned()];
+    let names = |(paths, errors): (Vec<PathBuf>, Vec<String>)| {
+        assert!(errors.is_empty(), "{:?}", errors);
+        paths.iter().map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(names(walk(&root, &WalkOptions::default())), vec!["src/main.rs", "src/notes.txt"]);
+    assert_eq!(names(walk(&root, &WalkOptions { hidden: true, ..Default::default() })),
+               vec![".gitignore", ".hidden/secret.rs", "src/main.rs", "src/notes.txt"]);
+    assert_eq!(names(walk(&root, &WalkOptions { no_ignore: true, ..Default::default() })),
+               vec!["src/main.rs", "src/notes.txt", "target/out.rs"]);
+    assert_eq!(names(walk(&root, &WalkOptions { include: vec!["*.txt".to_string()], ..Default::default() })),
+               vec!["src/notes.txt"]);
+    assert_eq!(names(walk(&root, &WalkOptions { exclude: vec!["*.txt".to_string()], ..Default::default() })),
+               vec!["src/main.rs"]);
+    assert_eq!(names(walk(&root, &WalkOptions { types: vec!["rust".to_string()], ..Default::default() })),
+               vec!["src/main.rs"]);
+
+    let (_, errors) = walk(&root, &WalkOptions { types: vec!["no-such-type".to_string()], ..Default::default() });
+    assert_eq!(errors.len(), 1);
+
+    let wide: Vec<u8> = "wide".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    fs::write(dir.join("wide.txt"), wide).unwrap();
+    assert!(!is_binary(&dir.join("wide.txt")).unwrap() && is_binary(&dir.join("src/image.png")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
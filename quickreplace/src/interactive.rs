@@ -0,0 +1,189 @@
+use std::io::{self, BufRead, Write};
+
+use text_colorizer::*;
+
+use crate::matcher::{Matcher, Replacements};
+use crate::scope::Regions;
+use crate::template::Template;
+
+/*
+Interactive mode
+
+Instead of letting 'replace_all' rewrite every occurrence, --interactive shows
+each match in context, with its line number, the match highlighted and the
+line as it would read after replacement, and asks what to do with it:
+
+    y  replace this match
+    n  leave this match alone
+    a  replace this match and every later one, in every file, without asking
+    q  leave this match and every later one alone; answers already given stand
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+// What has been answered so far; it carries over from one file to the next.
+#[derive(Debug, Default)]
+pub struct Session {
+    all: bool,
+    quit: bool,
+}
+
+// One match, as shown to the user.
+pub struct Prompt<'a> {
+    pub path: &'a str,
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+    pub replacement: &'a str,
+}
+
+impl Session {
+    /*
+    Replace the matches of 'matcher' in 'text' that 'ask' agrees to, returning
+    the new text and the byte range of each match replaced, with the length
+    of its replacement. With 'regions'
+    only the matches within them are considered at all.
+     */
+    pub fn replace(&mut self, path: &str, text: &str, matcher: &Matcher, replacement: &Template,
+                   regions: Option<&Regions>,
+                   mut ask: impl FnMut(&Prompt) -> Answer) -> Result<(String, Replacements), String> {
+        let mut accepted = Vec::new();
+        let in_scope = |start, end| regions.is_none_or(|regions| regions.contains(start, end));
+
+        for m in matcher.matches_where(text, replacement, in_scope)? {
+            let accept = if self.quit {
+                false
+            } else if self.all {
+                true
+            } else {
+                match ask(&Prompt { path, text, start: m.start, end: m.end, replacement: &m.replacement }) {
+                    Answer::Yes => true,
+                    Answer::No => false,
+                    Answer::All => {
+                        self.all = true;
+                        true
+                    }
+                    Answer::Quit => {
+                        self.quit = true;
+                        false
+                    }
+                }
+            };
+
+            if accept {
+                accepted.push(m);
+            }
+        }
+        Ok((crate::matcher::splice(text, &accepted), crate::matcher::replacements(&accepted)))
+    }
+
+    // Whether the user has quit, so there's no point looking at more files.
+    pub fn finished(&self) -> bool {
+        self.quit
+    }
+}
+
+#[test]
+fn test_session_replace() {
+    let regex = Matcher::new(r"(\d+)", &Default::default()).unwrap();
+    let template = Template::parse("<$1>", false).unwrap();
+    let text = "1 2 3 4";
+
+    let mut answers = vec![Answer::Yes, Answer::No, Answer::Yes, Answer::No].into_iter();
+    let mut session = Session::default();
+    assert_eq!(session.replace("f", text, &regex, &template, None, |_| answers.next().unwrap()),
+               Ok(("<1> 2 <3> 4".to_string(), vec![(0, 1, 3), (4, 5, 3)])));
+
+    let mut asked = Vec::new();
+    let mut session = Session::default();
+    let (out, replaced) = session.replace("f", text, &regex, &template, None, |p| {
+        asked.push((p.start, p.replacement.to_string()));
+        if p.start == 0 { Answer::No } else { Answer::All }
+    }).unwrap();
+    assert_eq!((out.as_str(), replaced.len()), ("1 <2> <3> <4>", 3));
+    assert_eq!(asked, vec![(0, "<1>".to_string()), (2, "<2>".to_string())]);
+    // "all" carries over to the next file.
+    assert_eq!(session.replace("g", "5", &regex, &template, None, |_| panic!("asked again")),
+               Ok(("<5>".to_string(), vec![(0, 1, 3)])));
+
+    let mut session = Session::default();
+    assert_eq!(session.replace("f", text, &regex, &template, None, |_| Answer::Quit), Ok((text.to_string(), vec![])));
+    assert!(session.finished());
+}
+
+/*
+Show a match on the terminal and read the answer from stdin. End of input
+counts as quitting. The questions go to stderr, since stdout may be the
+replaced text.
+ */
+pub fn ask_terminal(prompt: &Prompt) -> Answer {
+    eprint!("{}", show(prompt));
+    loop {
+        eprint!("{} ", "Replace? [y]es, [n]o, [a]ll, [q]uit:".bold());
+        let _ = io::stderr().flush();
+
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return Answer::Quit,
+            Ok(_) => {}
+        }
+        match line.trim() {
+            "y" | "Y" | "yes" => return Answer::Yes,
+            "n" | "N" | "no" => return Answer::No,
+            "a" | "A" | "all" => return Answer::All,
+            "q" | "Q" | "quit" => return Answer::Quit,
+            _ => {}
+        }
+    }
+}
+
+/*
+The lines holding the match, once as they are with the match in red and once
+as they'd be with the replacement in green, plus a line of context on each
+side.
+ */
+fn show(prompt: &Prompt) -> String {
+    let text = prompt.text;
+    let line_start = text[..prompt.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[prompt.end..].find('\n').map_or(text.len(), |i| prompt.end + i);
+    let number = text[..line_start].matches('\n').count() + 1;
+
+    let before = &text[line_start..prompt.start];
+    let after = &text[prompt.end..line_end];
+    let old = format!("{}{}{}", before, text[prompt.start..prompt.end].red().bold(), after);
+    let new = format!("{}{}{}", before, prompt.replacement.green().bold(), after);
+
+    let mut out = format!("{}\n", format!("{}:{}", prompt.path, number).bold());
+    if line_start > 0 {
+        let previous_start = text[..line_start - 1].rfind('\n').map_or(0, |i| i + 1);
+        out += &format!("  {:>5} | {}\n", number - 1, &text[previous_start..line_start - 1]);
+    }
+    out += &prefix_lines(&old, &format!("{} {:>5} | ", "-".red(), number));
+    out += &prefix_lines(&new, &format!("{} {:>5} | ", "+".green(), number));
+    if line_end + 1 < text.len() {
+        let next_end = text[line_end + 1..].find('\n').map_or(text.len(), |i| line_end + 1 + i);
+        let last = number + text[prompt.start..line_end].matches('\n').count() + 1;
+        out += &format!("  {:>5} | {}\n", last, &text[line_end + 1..next_end]);
+    }
+    out
+}
+
+// Put 'prefix' in front of each line of 'text', which may span several lines.
+fn prefix_lines(text: &str, prefix: &str) -> String {
+    text.split('\n').map(|line| format!("{}{}\n", prefix, line)).collect()
+}
+
+#[test]
+fn test_show() {
+    let text = "first\nsecond foo here\nthird\n";
+    let start = text.find("foo").unwrap();
+    let shown = crate::diff::strip_colors(&show(&Prompt {
+        path: "a.txt", text, start, end: start + 3, replacement: "bar",
+    }));
+    assert_eq!(shown, "a.txt:2\n      1 | first\n-     2 | second foo here\n+     2 | second bar here\n      3 | third\n");
+}
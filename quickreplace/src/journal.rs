@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+use crate::files;
+
+/*
+Undo journal
+
+Every run that rewrites files in place writes a journal of what it did, so
+a bulk replacement that went wrong can be taken back even outside git. For
+each file changed it records the path, a hash of the contents before and
+after, and the bytes the run replaced: enough to put the file back without
+keeping a copy of all of it. --no-journal leaves the journal out.
+
+Journals live in $QUICKREPLACE_JOURNAL if that's set, and otherwise in
+$XDG_STATE_HOME/quickreplace/journal or ~/.local/state/quickreplace/journal,
+one file per run. 'quickreplace undo N' undoes the last N runs, newest
+first, and deletes their journals. A run whose files have changed since,
+so their hashes no longer match, is refused as a whole: undoing it would
+throw away the later edits.
+ */
+pub fn directory() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("QUICKREPLACE_JOURNAL") {
+        return Some(PathBuf::from(dir));
+    }
+    let state = env::var_os("XDG_STATE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    Some(state.join("quickreplace").join("journal"))
+}
+
+// One file a run changed.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    // Hashes of the contents before and after the run.
+    pub original: u64,
+    pub replaced: u64,
+    // In order, and not overlapping.
+    pub hunks: Hunks,
+}
+
+/*
+An entry's hunks: kept in memory, or for --stream, where a huge file may
+have more than would fit, written out as they come to a spill file in the
+journal directory, which the journal copies them from.
+ */
+#[derive(Debug)]
+pub enum Hunks {
+    Kept(Vec<Hunk>),
+    Spilled(Spill),
+}
+
+// Spilled hunks aren't read back just to compare them.
+impl PartialEq for Hunks {
+    fn eq(&self, other: &Hunks) -> bool {
+        matches!((self, other), (Hunks::Kept(a), Hunks::Kept(b)) if a == b)
+    }
+}
+
+// Undoing a hunk puts 'old' back in place of the 'len' bytes at 'offset' in the replaced file.
+#[derive(Debug, PartialEq)]
+pub struct Hunk {
+    pub offset: u64,
+    pub len: u64,
+    pub old: Vec<u8>,
+}
+
+impl Entry {
+    // The entry for a file changed from 'old' to 'new', found by diffing their lines.
+    pub fn between(path: &Path, old: &[u8], new: &[u8]) -> Entry {
+        let old_lines: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+        let new_lines: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+        // Where each line starts, and where the last one ends.
+        let starts = |lines: &[&[u8]]| {
+            let mut starts = vec![0];
+            for line in lines {
+                starts.push(starts.last().unwrap() + line.len());
+            }
+            starts
+        };
+        let (old_starts, new_starts) = (starts(&old_lines), starts(&new_lines));
+
+        let hunks = capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines).iter()
+            .filter(|op| op.tag() != DiffTag::Equal)
+            .map(|op| {
+                let (old_range, new_range) = (op.old_range(), op.new_range());
+                Hunk {
+                    offset: new_starts[new_range.start] as u64,
+                    len: (new_starts[new_range.end] - new_starts[new_range.start]) as u64,
+                    old: old[old_starts[old_range.start]..old_starts[old_range.end]].to_vec(),
+                }
+            })
+            .collect();
+        Entry { path: path.to_path_buf(), original: hash(old), replaced: hash(new), hunks: Hunks::Kept(hunks) }
+    }
+}
+
+// Hunks written to a file of their own as they come, removed again once dropped; see 'Hunks'.
+#[derive(Debug)]
+pub struct Spill {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Spill {
+    pub fn new(directory: &Path) -> io::Result<Spill> {
+        // Files worked on at the same time each need their own.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        fs::create_dir_all(directory)?;
+        let name = format!(".{}-{}.hunks", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+        let path = directory.join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Spill { path, file: BufWriter::new(file) })
+    }
+
+    pub fn push(&mut self, offset: u64, len: u64, old: &[u8]) -> io::Result<()> {
+        write_hunk(&mut self.file, offset, len, old)
+    }
+
+    fn copy_to(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(file, out)?;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn write_hunk(out: &mut impl Write, offset: u64, len: u64, old: &[u8]) -> io::Result<()> {
+    writeln!(out, "hunk {} {} {}", offset, len, old.len())?;
+    out.write_all(old)?;
+    out.write_all(b"\n")
+}
+
+/*
+A path as the journal keeps it. On Unix a file name is any bytes, so they're
+kept as they are, not as UTF-8; elsewhere a path that isn't valid Unicode
+can't be recorded faithfully.
+ */
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/*
+The journal of one run. The file is only created once there's something to
+record, and each entry is synced as it's added, so a run that dies halfway
+can still be undone as far as it got.
+ */
+pub struct Journal {
+    path: PathBuf,
+    command: String,
+    file: Option<File>,
+}
+
+impl Journal {
+    pub fn new(directory: &Path, command: &[String]) -> Journal {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // Names sort in the order the runs started.
+        let name = format!("{:012}.{:09}-{}.journal", now.as_secs(), now.subsec_nanos(), std::process::id());
+        Journal { path: directory.join(name), command: command.join(" ").replace('\n', " "), file: None }
+    }
+
+    pub fn record(&mut self, entry: &mut Entry) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                fs::create_dir_all(self.path.parent().unwrap())?;
+                let mut file = OpenOptions::new().append(true).create_new(true).open(&self.path)?;
+                write!(file, "quickreplace journal 1\ncommand {}\n", self.command)?;
+                self.file.insert(file)
+            }
+        };
+
+        let path = path_to_bytes(&fs::canonicalize(&entry.path)?);
+        let mut out = BufWriter::new(file);
+        writeln!(out, "file {}", path.len())?;
+        out.write_all(&path)?;
+        write!(out, "\nhashes {:016x} {:016x}\n", entry.original, entry.replaced)?;
+        match &mut entry.hunks {
+            Hunks::Kept(hunks) => for hunk in hunks {
+                write_hunk(&mut out, hunk.offset, hunk.len, &hunk.old)?;
+            },
+            Hunks::Spilled(spill) => spill.copy_to(&mut out)?,
+        }
+        out.write_all(b"end\n")?;
+        out.into_inner().map_err(io::IntoInnerError::into_error)?.sync_data()
+    }
+}
+
+// A run's journal, read back to undo it.
+#[derive(Debug)]
+pub struct Run {
+    // The command line that made it.
+    pub command: String,
+    pub entries: Vec<Entry>,
+}
+
+// The journals in 'directory', newest first.
+pub fn runs(directory: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("failed to read journal directory '{}': {:?}", directory.display(), e)),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("failed to read journal directory '{}': {:?}", directory.display(), e))?
+            .path();
+        if path.extension().is_some_and(|extension| extension == "journal") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths.reverse();
+    Ok(paths)
+}
+
+pub fn read_run(path: &Path) -> Result<Run, String> {
+    let data = fs::read(path).map_err(|e| format!("failed to read journal '{}': {:?}", path.display(), e))?;
+    let (command, entries) = parse_run(&data).ok_or_else(|| format!("journal '{}' is damaged", path.display()))?;
+    Ok(Run { command, entries })
+}
+
+fn parse_run(data: &[u8]) -> Option<(String, Vec<Entry>)> {
+    let mut cursor = Cursor(data);
+    if cursor.line()? != "quickreplace journal 1" {
+        return None;
+    }
+    let command = cursor.line()?.strip_prefix("command ")?.to_string();
+
+    let mut entries = Vec::new();
+    while !cursor.0.is_empty() {
+        let length = cursor.line()?.strip_prefix("file ")?.parse().ok()?;
+        let path = path_from_bytes(cursor.bytes(length)?)?;
+        let (original, replaced) = cursor.line()?.strip_prefix("hashes ")?.split_once(' ')?;
+        let (original, replaced) = (u64::from_str_radix(original, 16).ok()?, u64::from_str_radix(replaced, 16).ok()?);
+
+        let mut hunks: Vec<Hunk> = Vec::new();
+        loop {
+            let line = cursor.line()?;
+            if line == "end" {
+                break;
+            }
+            let mut fields = line.strip_prefix("hunk ")?.split(' ').map(|field| field.parse::<u64>().ok());
+            let (offset, len, old_len) = (fields.next()??, fields.next()??, fields.next()??);
+            if hunks.last().is_some_and(|last| offset < last.offset + last.len) {
+                return None;
+            }
+            let old = cursor.bytes(usize::try_from(old_len).ok()?)?.to_vec();
+            hunks.push(Hunk { offset, len, old });
+        }
+        entries.push(Entry { path, original, replaced, hunks: Hunks::Kept(hunks) });
+    }
+    Some((command, entries))
+}
+
+// Reads a journal: lines of text, some followed by a given number of raw bytes.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn line(&mut self) -> Option<&'a str> {
+        let end = self.0.iter().position(|&b| b == b'\n')?;
+        let line = std::str::from_utf8(&self.0[..end]).ok()?;
+        self.0 = &self.0[end + 1..];
+        Some(line)
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.get(count) != Some(&b'\n') {
+            return None;
+        }
+        let bytes = &self.0[..count];
+        self.0 = &self.0[count + 1..];
+        Some(bytes)
+    }
+}
+
+/*
+Make sure every file in 'run' is as the run left it. A file the run changed
+twice, by being named twice, need only match its last change.
+ */
+pub fn check(run: &Run) -> Result<(), String> {
+    let mut expected: HashMap<&Path, u64> = HashMap::new();
+    for entry in run.entries.iter().rev() {
+        let current = match expected.get(entry.path.as_path()) {
+            Some(&hash) => hash,
+            None => hash_file(&entry.path)
+                .map_err(|e| format!("failed to read from file '{}': {:?}", entry.path.display(), e))?,
+        };
+        if current != entry.replaced {
+            return Err(format!("'{}' has changed since", entry.path.display()));
+        }
+        expected.insert(&entry.path, entry.original);
+    }
+    Ok(())
+}
+
+/*
+Put a file back as it was before the run, a stretch at a time, so undoing a
+--stream run over a huge file takes no more memory than the run did. If the
+result isn't the original after all, the file is left alone.
+ */
+pub fn restore(entry: &Entry) -> Result<(), String> {
+    let Hunks::Kept(hunks) = &entry.hunks else {
+        return Err(format!("failed to restore '{}': its hunks were never read back", entry.path.display()));
+    };
+    let mut restored = None;
+    files::rewrite_atomically(&entry.path, files::RewriteOptions::default(), |file| {
+        let mut input = BufReader::new(File::open(&entry.path)?);
+        let mut output = Hashing::new(file);
+        let mut at = 0;
+        for hunk in hunks {
+            io::copy(&mut (&mut input).take(hunk.offset - at), &mut output)?;
+            io::copy(&mut (&mut input).take(hunk.len), &mut io::sink())?;
+            output.write_all(&hunk.old)?;
+            at = hunk.offset + hunk.len;
+        }
+        io::copy(&mut input, &mut output)?;
+        restored = Some(output.hash());
+        Ok(restored == Some(entry.original))
+    }).map_err(|e| format!("failed to restore '{}': {:?}", entry.path.display(), e))?;
+
+    if restored != Some(entry.original) {
+        return Err(format!("failed to restore '{}': the journal doesn't fit it", entry.path.display()));
+    }
+    Ok(())
+}
+
+/*
+FNV-1a: no use against someone out to fool it, but stable from one build to
+the next, which a journal kept for later needs, and plenty to tell that a
+file has been edited.
+ */
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    fnv(FNV_OFFSET, bytes)
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = Hashing::new(File::open(path)?);
+    io::copy(&mut file, &mut io::sink())?;
+    Ok(file.hash())
+}
+
+// Passes reads or writes through, hashing the bytes on the way.
+pub struct Hashing<T> {
+    inner: T,
+    hash: u64,
+}
+
+impl<T> Hashing<T> {
+    pub fn new(inner: T) -> Hashing<T> {
+        Hashing { inner, hash: FNV_OFFSET }
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buffer)?;
+        self.hash = fnv(self.hash, &buffer[..count]);
+        Ok(count)
+    }
+}
+
+impl<R: BufRead> BufRead for Hashing<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // With something consumed the buffer isn't empty, so this hands it back without reading.
+        if amount > 0 {
+            if let Ok(buffer) = self.inner.fill_buf() {
+                self.hash = fnv(self.hash, &buffer[..amount]);
+            }
+        }
+        self.inner.consume(amount)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buffer)?;
+        self.hash = fnv(self.hash, &buffer[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_journal() {
+    let dir = env::temp_dir().join(format!("quickreplace-journal-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+    let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+    let old = "one\ntwo\nthree\nfour";
+    let new = "one\n2\nthree\nfour\nfive";
+    fs::write(&a, new).unwrap();
+    fs::write(&b, "x\n").unwrap();
+
+    let mut entry = Entry::between(&a, old.as_bytes(), new.as_bytes());
+    assert_eq!(entry.hunks, Hunks::Kept(vec![Hunk { offset: 4, len: 2, old: b"two\n".to_vec() },
+                                             Hunk { offset: 12, len: 9, old: b"four".to_vec() }]));
+
+    let mut journal = Journal::new(&dir.join("journal"), &["quickreplace".to_string(), "-i".to_string()]);
+    journal.record(&mut entry).unwrap();
+    journal.record(&mut Entry::between(&b, b"x\n", b"x\n")).unwrap();
+    let paths = runs(&dir.join("journal")).unwrap();
+    assert_eq!(paths.len(), 1);
+    let run = read_run(&paths[0]).unwrap();
+    assert_eq!(run.command, "quickreplace -i");
+    assert_eq!(run.entries[0], entry);
+
+    check(&run).unwrap();
+    restore(&run.entries[0]).unwrap();
+    assert_eq!(fs::read_to_string(&a).unwrap(), old);
+    assert!(check(&run).unwrap_err().contains("has changed since"));
+    assert!(restore(&run.entries[0]).is_err());
+    assert_eq!(fs::read_to_string(&a).unwrap(), old);
+
+    let mut hashing = Hashing::new(&b"abc\ndef"[..]);
+    let mut line = String::new();
+    hashing.read_line(&mut line).unwrap();
+    hashing.read_line(&mut line).unwrap();
+    assert_eq!(hashing.hash(), hash(b"abc\ndef"));
+    assert!(parse_run(b"quickreplace journal 1\ncommand x\nfile 3\nabc\n").is_none());
+
+    // Spilled hunks come out of the journal as if they'd been kept, and the spill file goes.
+    let mut spill = Spill::new(&dir.join("journal")).unwrap();
+    spill.push(0, 1, b"x\n").unwrap();
+    let spilled = spill.path.clone();
+    fs::write(&b, "y\n").unwrap();
+    let mut journal = Journal::new(&dir.join("journal"), &["quickreplace".to_string()]);
+    journal.record(&mut Entry { path: b.clone(), original: hash(b"x\n"), replaced: hash(b"y\n"),
+                                hunks: Hunks::Spilled(spill) }).unwrap();
+    assert!(!spilled.exists());
+    let run = read_run(&runs(&dir.join("journal")).unwrap()[0]).unwrap();
+    assert_eq!(run.entries[0].hunks, Hunks::Kept(vec![Hunk { offset: 0, len: 1, old: b"x\n".to_vec() }]));
+
+    // A file name that isn't UTF-8 is kept as it is, and the file can be found and put back.
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let odd = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        fs::write(&odd, "new\n").unwrap();
+        let mut journal = Journal::new(&dir.join("journal"), &["quickreplace".to_string()]);
+        journal.record(&mut Entry::between(&odd, b"old\n", b"new\n")).unwrap();
+        let run = read_run(&runs(&dir.join("journal")).unwrap()[0]).unwrap();
+        assert_eq!(run.entries[0].path, odd);
+        restore(&run.entries[0]).unwrap();
+        assert_eq!(fs::read_to_string(&odd).unwrap(), "old\n");
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,11 +1,73 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+
+use crossbeam::channel;
+
+mod archive;
+mod diff;
+mod encoding;
+mod endings;
+mod files;
+mod interactive;
+mod journal;
+mod matcher;
+mod report;
+mod rules;
+mod scope;
+mod search;
+mod stream;
+mod syntax;
+mod template;
+mod trail;
+mod watch;
 
 #[derive(Debug)]
 struct Arguments {
     target: String,
     replacement: String,
-    filename: String,
-    output: String,
+    inputs: Vec<String>,
+    output: Option<String>,
+    in_place: bool,
+    backup: bool,
+    keep_mtime: bool,
+    // --watch: keep replacing as the files change; see 'watch'.
+    watch: bool,
+    recursive: bool,
+    walk: files::WalkOptions,
+    dry_run: bool,
+    interactive: bool,
+    matching: matcher::MatchOptions,
+    // --encoding: what every file is in, instead of guessing; see 'encoding'.
+    encoding: Option<&'static encoding_rs::Encoding>,
+    stream: bool,
+    bytes: bool,
+    // With --rules, 'target' and 'replacement' are empty and the rules come from this file.
+    rules_file: Option<String>,
+    scope: scope::ScopeOptions,
+    no_journal: bool,
+    // How many files to work on at once.
+    jobs: usize,
+    json: bool,
+    // Set for 'search', which only lists matches; 'replacement' is empty.
+    search: Option<search::SearchOptions>,
+}
+
+impl Arguments {
+    // Whether this run records the files it rewrites, so 'undo' can put them back.
+    fn journaling(&self) -> bool {
+        self.in_place && !self.dry_run && !self.no_journal
+    }
+
+    // Whether each file rewritten gets a journal::Entry, for the journal or for --watch to know its own writes.
+    fn entries(&self) -> bool {
+        self.journaling() || (self.watch && !self.dry_run)
+    }
+
+    fn rewrite(&self) -> files::RewriteOptions {
+        files::RewriteOptions { backup: self.backup, keep_mtime: self.keep_mtime }
+    }
 }
 
 /*
@@ -17,62 +79,1379 @@ use text_colorizer::*;
 
 fn print_usage() {
     eprintln!("{} - change occurrences of one string into another","quickreplace".green());
-    eprintln!("Usage: quickreplace <target> <replacement> <INPUT> <OUTPUT>");
+    eprintln!("Usage: quickreplace [OPTIONS] <target> <replacement> [INPUT] [OUTPUT]");
+    eprintln!("       quickreplace [OPTIONS] <target> <replacement> --in-place <FILE|GLOB>...");
+    eprintln!("       quickreplace [OPTIONS] <target> <replacement> --recursive <PATH>...");
+    eprintln!("       quickreplace [OPTIONS] --rules FILE [INPUT] [OUTPUT], and likewise with -i or -r");
+    eprintln!("       quickreplace search [OPTIONS] <target> [FILE|GLOB|PATH]...   list matches, replacing nothing");
+    eprintln!("       quickreplace undo [N]   put back the files the last N runs (default 1) rewrote in place");
+    eprintln!("An INPUT or OUTPUT of '-', or one left out, means stdin or stdout.");
+    eprintln!("In <replacement> $1 and $name are capture groups, \\U...\\E, \\L...\\E, \\u and \\l change case,");
+    eprintln!("$# is the number of the match and $(EXPR) is integer arithmetic, e.g. $($1 + 1).");
+    eprintln!("Text files in .gz files and .zip, .tar and .tgz archives are replaced in too, as FILE:MEMBER.");
+    eprintln!("Options:");
+    eprintln!("    -i, --in-place        rewrite every input file in place");
+    eprintln!("        --backup          with --in-place, keep each original as FILE.bak");
+    eprintln!("        --keep-mtime      with --in-place, keep each file's modification time");
+    eprintln!("        --no-journal      with --in-place, don't record the changes for 'undo'");
+    eprintln!("        --watch           with --in-place, keep watching and replace again in each file that changes");
+    eprintln!("    -r, --recursive       walk directories and rewrite the files in them (implies --in-place),");
+    eprintln!("                          honouring .gitignore and .ignore files and skipping hidden and binary files");
+    eprintln!("        --hidden          with --recursive, include hidden files and directories");
+    eprintln!("        --no-ignore       with --recursive, don't read .gitignore or .ignore files");
+    eprintln!("        --include GLOB    with --recursive, only rewrite files matching GLOB");
+    eprintln!("        --exclude GLOB    with --recursive, skip files matching GLOB");
+    eprintln!("    -t, --type TYPE       with --recursive, only rewrite files of TYPE (e.g. rust, py, js)");
+    eprintln!("    -n, --dry-run         print a diff of each change and the match counts; write nothing");
+    eprintln!("        --interactive     show each match and ask before replacing it");
+    eprintln!("    -F, --fixed-strings   treat <target> as plain text and <replacement> as-is, not as a regex");
+    eprintln!("    -w, --word-regexp     only replace whole-word matches");
+    eprintln!("        --ignore-case     match regardless of case (-i with search)");
+    eprintln!("        --multiline       let '^' and '$' match at the start and end of every line");
+    eprintln!("        --dot-all         let '.' match newlines too, so a match can run across lines");
+    eprintln!("        --verbose-regex   ignore whitespace in <target> and treat '#' as starting a comment");
+    eprintln!("        --stream          replace line by line, without reading whole files into memory");
+    eprintln!("        --bytes           match raw bytes, so input needn't be UTF-8");
+    eprintln!("        --encoding NAME   read and write files as NAME (e.g. latin1, utf-16le) instead of guessing");
+    eprintln!("    -f, --rules FILE      apply the sed-like rules in FILE, one 's/TARGET/REPLACEMENT/FLAGS' a line,");
+    eprintln!("                          in order, instead of <target> and <replacement>");
+    eprintln!("        --lines RANGE     only replace on lines N, N:M, N: or :M; a comma-separated list is fine");
+    eprintln!("        --line-matching REGEX");
+    eprintln!("                          only replace on lines REGEX matches");
+    eprintln!("        --from REGEX      only replace from a line REGEX matches...");
+    eprintln!("        --to REGEX        ...through the next line the --to REGEX matches");
+    eprintln!("        --only PART       only replace in code, comments or strings, going by each file's extension");
+    eprintln!("        --language LANG   with --only, read every file as rust, c (also C++, Java, JS, Go...),");
+    eprintln!("                          python or json");
+    eprintln!("    -j, --jobs N          work on N files at once (default: one per CPU, or one with --stream)");
+    eprintln!("        --json            print a JSON report of the files, match counts and byte offsets");
+    eprintln!("        --                treat everything after this as <target>, <replacement> and inputs;");
+    eprintln!("                          'quickreplace -- undo ...' replaces the word 'undo'");
+    eprintln!("Search options:");
+    eprintln!("    -c, --count           print the number of matches in each file");
+    eprintln!("    -l, --files-with-matches");
+    eprintln!("                          print only the names of files with a match");
+    eprintln!("    -C, --context N       show N lines around each match");
+    eprintln!("The exit status is 0 if anything was replaced or found, 1 if nothing was, 2 for bad arguments,");
+    eprintln!("3 for a bad pattern, 4 if every file failed and 5 if only some did.");
 }
 
 use std::env;
 
-fn parse_args() -> Arguments {
+fn parse_args(args: &[String]) -> Arguments {
+    match parse_arg_list(args) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            print_usage();
+            eprintln!("{} {}", "Error:".red().bold(), message);
+            std::process::exit(Status::Usage.code());
+        }
+    }
+}
 
-    let args: Vec<String> = env::args().skip(1).collect(); // the first argument is the program name so can be ignored
+/*
+Options can appear anywhere before a '--'; everything else is positional.
+Without --in-place we keep the original four-argument form, so the last
+positional argument is the output file. Like sed, the input and output can
+be left out, or given as '-', to read stdin and write stdout. A first
+argument of 'search' takes just <target> and any number of inputs.
+ */
+fn parse_arg_list(args: &[String]) -> Result<Arguments, String> {
+    let (searching, args) = match args.split_first() {
+        Some((first, rest)) if first == "search" => (true, rest),
+        _ => (false, args),
+    };
+    let mut positional = Vec::new();
+    let mut in_place = false;
+    let mut backup = false;
+    let mut keep_mtime = false;
+    let mut watch = false;
+    let mut recursive = false;
+    let mut walk = files::WalkOptions::default();
+    let mut dry_run = false;
+    let mut interactive = false;
+    let mut matching = matcher::MatchOptions::default();
+    let mut encoding = None;
+    let mut stream = false;
+    let mut bytes = false;
+    let mut rules_file = None;
+    let mut scope = scope::ScopeOptions::default();
+    let mut no_journal = false;
+    let mut jobs = None;
+    let mut json = false;
+    let mut search = search::SearchOptions::default();
+    let mut options_done = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if options_done || !arg.starts_with('-') || arg == "-" {
+            positional.push(arg.clone());
+            continue;
+        }
 
-    if args.len() != 4 {
-        print_usage();
-        eprintln!("{} wrong number of arguments: expected 4, got {}.", "Error:".red().bold(), args.len());
-        std::process::exit(1);
+        // Options taking a value accept both '--name VALUE' and '--name=VALUE'.
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || match &inline_value {
+            Some(v) => Ok(v.clone()),
+            None => args.next().cloned().ok_or_else(|| format!("option '{}' needs a value", name)),
+        };
+
+        match name {
+            "--" => options_done = true,
+            // To search, as to grep, '-i' is --ignore-case; there's nothing to rewrite in place.
+            "-i" if searching => matching.ignore_case = true,
+            "-i" | "--in-place" => in_place = true,
+            "--backup" => backup = true,
+            "--keep-mtime" => keep_mtime = true,
+            "--no-journal" => no_journal = true,
+            "--watch" => watch = true,
+            "-r" | "--recursive" => recursive = true,
+            "--hidden" => walk.hidden = true,
+            "--no-ignore" => walk.no_ignore = true,
+            "--include" => walk.include.push(value()?),
+            "--exclude" => walk.exclude.push(value()?),
+            "-t" | "--type" => walk.types.push(value()?),
+            "-n" | "--dry-run" => dry_run = true,
+            "--interactive" => interactive = true,
+            "-F" | "--fixed-strings" => matching.fixed_strings = true,
+            "-w" | "--word-regexp" => matching.word = true,
+            "--ignore-case" => matching.ignore_case = true,
+            "--multiline" => matching.multi_line = true,
+            "--dot-all" => matching.dot_all = true,
+            "--verbose-regex" => matching.verbose = true,
+            "--stream" => stream = true,
+            "--bytes" => bytes = true,
+            "--encoding" => encoding = Some(encoding::lookup(&value()?)?),
+            "-f" | "--rules" => rules_file = Some(value()?),
+            "--lines" => scope.lines.push(value()?),
+            "--line-matching" => scope.line_matching = Some(value()?),
+            "--from" => scope.from = Some(value()?),
+            "--to" => scope.to = Some(value()?),
+            "--only" => scope.only = Some(syntax::Part::from_name(&value()?)?),
+            "--language" => scope.language = Some(syntax::Language::from_name(&value()?)?),
+            "--json" => json = true,
+            "-j" | "--jobs" => {
+                let count = value()?;
+                jobs = Some(count.parse().ok().filter(|&n| n > 0)
+                    .ok_or_else(|| format!("expected a number of jobs, got '{}'", count))?);
+            }
+            "-c" | "--count" => search.count = true,
+            "-l" | "--files-with-matches" => search.files_only = true,
+            "-C" | "--context" => {
+                let count = value()?;
+                search.context = count.parse()
+                    .map_err(|_| format!("expected a number of context lines, got '{}'", count))?;
+            }
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+    }
+
+    let walking = walk.hidden || walk.no_ignore || !walk.include.is_empty() ||
+        !walk.exclude.is_empty() || !walk.types.is_empty();
+    if walking && !recursive {
+        return Err("--hidden, --no-ignore, --include, --exclude and --type only make sense with --recursive"
+            .to_string());
+    }
+    if searching {
+        if in_place || backup || keep_mtime || no_journal || watch || dry_run || interactive || stream ||
+            rules_file.is_some() {
+            return Err("search doesn't take --in-place, --backup, --keep-mtime, --no-journal, --watch, --dry-run, \
+                        --interactive, --stream or --rules".to_string());
+        }
+        if search.count && search.files_only {
+            return Err("-c and -l can't be combined".to_string());
+        }
+    } else if search != search::SearchOptions::default() {
+        return Err("-c, -l and -C only make sense with search".to_string());
+    }
+    in_place |= recursive && !searching;
+
+    if backup && !in_place {
+        return Err("--backup only makes sense with --in-place".to_string());
+    }
+    if keep_mtime && !in_place {
+        return Err("--keep-mtime only makes sense with --in-place".to_string());
+    }
+    if no_journal && !in_place {
+        return Err("--no-journal only makes sense with --in-place".to_string());
+    }
+    if watch && !in_place {
+        return Err("--watch only makes sense with --in-place".to_string());
+    }
+    // Neither the questions nor the one report at the end fit a run that never ends.
+    if watch && (interactive || json) {
+        return Err("--watch can't be combined with --interactive or --json".to_string());
+    }
+
+    if scope.to.is_some() && scope.from.is_none() {
+        return Err("--to only makes sense with --from".to_string());
+    }
+    if scope.language.is_some() && scope.only.is_none() {
+        return Err("--language only makes sense with --only".to_string());
+    }
+    // Comments and strings can run across lines, which --stream never sees together.
+    if scope.only.is_some() && stream {
+        return Err("--only can't be combined with --stream".to_string());
     }
 
-    Arguments {
-        target: args[0].clone(),
-        replacement: args[1].clone(),
-        filename: args[2].clone(),
-        output: args[3].clone()
+    if encoding.is_some() && (stream || bytes) {
+        return Err("--encoding can't be combined with --stream or --bytes, which leave bytes as they are".to_string());
     }
+    if interactive && (stream || bytes) {
+        return Err("--interactive can't be combined with --stream or --bytes".to_string());
+    }
+    // Both want the terminal's stdout to themselves.
+    if interactive && json {
+        return Err("--interactive can't be combined with --json".to_string());
+    }
+
+    // With --rules there's no <target> or <replacement>, so every positional argument is a file.
+    let (target, replacement) = if searching {
+        if positional.is_empty() {
+            return Err("expected a <target> to search for".to_string());
+        }
+        (positional.remove(0), String::new())
+    } else if rules_file.is_some() {
+        (String::new(), String::new())
+    } else if positional.len() < 2 {
+        return Err(format!("expected <target> and <replacement>, got {} arguments", positional.len()));
+    } else {
+        let mut pair = positional.drain(..2);
+        (pair.next().unwrap(), pair.next().unwrap())
+    };
+
+    let (inputs, output) = if searching {
+        if positional.is_empty() {
+            positional.push(if recursive { "." } else { files::STDIO }.to_string());
+        }
+        (positional, None)
+    } else if in_place {
+        if positional.is_empty() {
+            return Err("expected at least one input to rewrite in place".to_string());
+        }
+        if positional.iter().any(|input| input == files::STDIO) {
+            return Err("stdin can't be rewritten in place".to_string());
+        }
+        (positional, None)
+    } else {
+        if positional.len() > 2 {
+            return Err(format!("too many arguments: expected at most <INPUT> and <OUTPUT>, got {} files.",
+                               positional.len()));
+        }
+        let input = positional.first().cloned().unwrap_or_else(|| files::STDIO.to_string());
+        let output = positional.get(1).cloned().unwrap_or_else(|| files::STDIO.to_string());
+        if json && !dry_run && output == files::STDIO {
+            return Err("--json needs stdout for the report; give an OUTPUT file for the replaced text".to_string());
+        }
+        // The answers would be read from the very stream we're replacing in.
+        if interactive && input == files::STDIO {
+            return Err("--interactive needs a named input file, since it reads answers from stdin".to_string());
+        }
+        (vec![input], Some(output))
+    };
+
+    Ok(Arguments {
+        target,
+        replacement,
+        inputs,
+        output,
+        in_place,
+        backup,
+        keep_mtime,
+        watch,
+        recursive,
+        walk,
+        dry_run,
+        interactive,
+        matching,
+        encoding,
+        stream,
+        bytes,
+        rules_file,
+        scope,
+        no_journal,
+        // One at a time with --stream, so its diffs can be printed as they come; see 'replace_stream'.
+        jobs: jobs.unwrap_or_else(|| match stream {
+            true => 1,
+            false => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }),
+        json,
+        search: searching.then_some(search),
+    })
 }
 
-use regex::Regex;
-fn replace(target: &str, replacement: &str, text: &str) -> Result<String, regex::Error>{
-    let regex = Regex::new(target)?;
-    Ok(regex.replace_all(text, replacement).to_string())
+#[test]
+fn test_parse_arg_list() {
+    let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let classic = parse_arg_list(&args(&["a", "b", "in.txt", "out.txt"])).unwrap();
+    assert_eq!(classic.inputs, vec!["in.txt"]);
+    assert_eq!(classic.output.as_deref(), Some("out.txt"));
+    assert!(!classic.in_place);
+
+    let in_place = parse_arg_list(&args(&["-i", "a", "b", "src/*.rs", "README.md", "--backup"])).unwrap();
+    assert_eq!(in_place.inputs, vec!["src/*.rs", "README.md"]);
+    assert!(in_place.in_place && in_place.backup && in_place.output.is_none());
+    assert!(in_place.journaling() && !in_place.keep_mtime);
+    assert!(parse_arg_list(&args(&["-i", "--keep-mtime", "a", "b", "f"])).unwrap().rewrite().keep_mtime);
+    assert!(!parse_arg_list(&args(&["-i", "--no-journal", "a", "b", "f"])).unwrap().journaling());
+    assert!(!parse_arg_list(&args(&["-i", "--dry-run", "a", "b", "f"])).unwrap().journaling());
+
+    let dashed = parse_arg_list(&args(&["--in-place", "--", "-x", "-y", "f"])).unwrap();
+    assert_eq!((dashed.target.as_str(), dashed.replacement.as_str()), ("-x", "-y"));
+
+    let recursive = parse_arg_list(&args(&["-r", "a", "b", ".", "--include", "*.rs", "--exclude=target/*",
+                                            "-t", "py", "--hidden"])).unwrap();
+    assert!(recursive.recursive && recursive.in_place && recursive.walk.hidden);
+    assert_eq!(recursive.walk.include, vec!["*.rs"]);
+    assert_eq!(recursive.walk.exclude, vec!["target/*"]);
+    assert_eq!(recursive.walk.types, vec!["py"]);
+
+    let dry_run = parse_arg_list(&args(&["--dry-run", "a", "b", "in.txt", "out.txt"])).unwrap();
+    assert!(dry_run.dry_run && !dry_run.in_place);
+
+    let literal = parse_arg_list(&args(&["-F", "-w", "--ignore-case", "a.b", "c", "in", "out"])).unwrap();
+    assert!(literal.matching.fixed_strings && literal.matching.word && literal.matching.ignore_case);
+    let lines = parse_arg_list(&args(&["--multiline", "--dot-all", "--verbose-regex", "a", "b"])).unwrap();
+    assert!(lines.matching.multi_line && lines.matching.dot_all && lines.matching.verbose);
+
+    let streaming = parse_arg_list(&args(&["--stream", "--bytes", "-i", "a", "b", "big.log"])).unwrap();
+    assert!(streaming.stream && streaming.bytes);
+    let latin1 = parse_arg_list(&args(&["--encoding", "latin1", "a", "b"])).unwrap();
+    assert_eq!(latin1.encoding.map(|e| e.name()), Some("windows-1252"));
+    assert!(parse_arg_list(&args(&["--encoding", "klingon", "a", "b"])).is_err());
+    assert!(parse_arg_list(&args(&["--encoding", "latin1", "--bytes", "a", "b"])).is_err());
+
+    let parallel = parse_arg_list(&args(&["-j", "3", "-r", "a", "b", "."])).unwrap();
+    assert_eq!(parallel.jobs, 3);
+    assert_eq!(parse_arg_list(&args(&["--stream", "-i", "a", "b", "f"])).unwrap().jobs, 1);
+    assert_eq!(parse_arg_list(&args(&["--stream", "-j", "4", "-i", "a", "b", "f"])).unwrap().jobs, 4);
+
+    assert!(parse_arg_list(&args(&["--json", "-i", "a", "b", "f"])).unwrap().json);
+    assert!(parse_arg_list(&args(&["--json", "a", "b", "in"])).is_err());
+    assert!(parse_arg_list(&args(&["--json", "--dry-run", "a", "b", "in"])).is_ok());
+
+    let search = parse_arg_list(&args(&["search", "-r", "-C", "2", "foo"])).unwrap();
+    assert_eq!((search.target.as_str(), search.inputs.clone()), ("foo", vec![".".to_string()]));
+    assert_eq!(search.search.map(|options| options.context), Some(2));
+    assert!(search.recursive && !search.in_place);
+    let counting = parse_arg_list(&args(&["search", "-c", "foo", "a.txt", "b.txt"])).unwrap();
+    assert_eq!(counting.inputs, vec!["a.txt", "b.txt"]);
+    let ignoring = parse_arg_list(&args(&["search", "-i", "foo", "a.txt"])).unwrap();
+    assert!(ignoring.matching.ignore_case && !ignoring.in_place);
+    assert!(parse_arg_list(&args(&["search", "--in-place", "foo", "a.txt"])).is_err());
+    assert!(parse_arg_list(&args(&["search", "-c", "-l", "foo"])).is_err());
+    assert!(parse_arg_list(&args(&["-c", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["search"])).is_err());
+
+    let piped = parse_arg_list(&args(&["a", "b"])).unwrap();
+    assert_eq!((piped.inputs, piped.output.as_deref()), (vec!["-".to_string()], Some("-")));
+    let to_stdout = parse_arg_list(&args(&["a", "b", "in.txt"])).unwrap();
+    assert_eq!((to_stdout.inputs, to_stdout.output.as_deref()), (vec!["in.txt".to_string()], Some("-")));
+
+    let rules = parse_arg_list(&args(&["-f", "renames.txt", "-i", "a.txt", "b.txt"])).unwrap();
+    assert_eq!(rules.rules_file.as_deref(), Some("renames.txt"));
+    assert_eq!(rules.inputs, vec!["a.txt", "b.txt"]);
+    assert!(rules.target.is_empty());
+
+    let scoped = parse_arg_list(&args(&["--lines", "1:5", "--lines=9", "--from", "^\\[db\\]", "--to=^\\[",
+                                         "a", "b", "in"])).unwrap();
+    assert_eq!(scoped.scope.lines, vec!["1:5", "9"]);
+    assert_eq!((scoped.scope.from.as_deref(), scoped.scope.to.as_deref()), (Some("^\\[db\\]"), Some("^\\[")));
+    let syntax = parse_arg_list(&args(&["--only", "code", "--language", "rust", "a", "b", "in"])).unwrap();
+    assert_eq!((syntax.scope.only, syntax.scope.language), (Some(syntax::Part::Code), Some(syntax::Language::Rust)));
+    assert!(parse_arg_list(&args(&["--only", "docs", "a", "b"])).is_err());
+    assert!(parse_arg_list(&args(&["--language", "rust", "a", "b"])).is_err());
+    assert!(parse_arg_list(&args(&["--only", "code", "--stream", "a", "b"])).is_err());
+
+    assert!(parse_arg_list(&args(&["a"])).is_err());
+    assert!(parse_arg_list(&args(&["--to", "x", "a", "b"])).is_err());
+    assert!(parse_arg_list(&args(&["a", "b", "c", "d", "e"])).is_err());
+    assert!(parse_arg_list(&args(&["-i", "a", "b", "-"])).is_err());
+    assert!(parse_arg_list(&args(&["--interactive", "a", "b", "-", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["-i", "a", "b", "f", "--include", "*.rs"])).is_err());
+    assert!(parse_arg_list(&args(&["-r", "a", "b", "f", "--include"])).is_err());
+    assert!(parse_arg_list(&args(&["-i", "a", "b"])).is_err());
+    assert!(parse_arg_list(&args(&["--backup", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["--no-journal", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["--keep-mtime", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["-r", "--watch", "a", "b", "."])).unwrap().entries());
+    assert!(parse_arg_list(&args(&["--watch", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["-i", "--watch", "--json", "a", "b", "f"])).is_err());
+    assert!(parse_arg_list(&args(&["-j", "0", "-i", "a", "b", "f"])).is_err());
+    assert!(parse_arg_list(&args(&["--interactive", "--stream", "a", "b", "in", "out"])).is_err());
+    assert!(parse_arg_list(&args(&["--frobnicate", "a", "b", "in", "out"])).is_err());
 }
 
+use matcher::{ByteMatcher, Matcher};
+use report::{FileReport, Members, Report, Status};
+use rules::Rule;
+use scope::Scope;
+use template::Template;
+
 fn main() {
-    let args = parse_args();
+    let args: Vec<String> = env::args().skip(1).collect(); // the first argument is the program name so can be ignored
+    if args.first().is_some_and(|arg| arg == "undo") {
+        std::process::exit(undo(&args[1..], &mut files::Printer::stdout()).code());
+    }
+    let arguments = parse_args(&args);
+    std::process::exit(run(&arguments, &mut files::Printer::stdout()).code());
+}
 
-    let data = match fs::read_to_string(&args.filename){
-        Ok(v) => v,
+/*
+The rules, each compiled for text or, with --stream and --bytes, for raw
+bytes, and paired with its replacement.
+ */
+enum Search {
+    Text(Vec<(Matcher, Template)>),
+    Bytes(Vec<(ByteMatcher, Template)>),
+}
+
+// What replacing in one file came to.
+struct Outcome {
+    // For each rule, the start and end of every match it replaced.
+    matches: Vec<Vec<(u64, u64)>>,
+    // With --stream, unless --json wants the offsets, just how many matches each rule had, in place of 'matches'.
+    counts: Option<Vec<usize>>,
+    changed: bool,
+    // What the undo journal needs to know, if this run keeps one and the file was rewritten.
+    entry: Option<journal::Entry>,
+    // What to print for the file, kept back so files are shown in order: with
+    // --dry-run the diff, and for 'search' the matches.
+    shown: String,
+    // For an archive, the members that had matches, with theirs; see 'archive'.
+    members: Members,
+}
+
+/*
+Replace in the input file, or stdin, and write the result to the output file
+or stdout, or with --in-place rewrite every file named or matched by
+'args.inputs', or with --recursive every file found under them. A file that
+fails is reported and skipped so one bad file doesn't stop the rest; the
+return value says how it all went, and with --json so does a report printed
+at the end; see 'report'.
+
+With --dry-run nothing is written, in any mode: each file that would change
+is shown as a unified diff instead, followed by a count of the matches.
+With --interactive each match is confirmed before it is replaced.
+
+Files are read whole as UTF-8 text unless --bytes says to take them as raw
+bytes, or --stream to go through them a line at a time; see 'stream'.
+With --rules every rule in the file is applied to each file in turn, and
+how often each one fired is printed at the end. --lines, --line-matching,
+--from and --to limit replacement to some lines of each file, and --only
+to its code, comments or strings; see 'scope' and 'syntax'.
+Every file rewritten in place is recorded in the undo journal; see 'journal'.
+Files are worked on --jobs at a time, except with --interactive, but always
+reported in order. 'search' goes through the files the same way, but only
+lists the matches; see 'search'. With --watch, it all starts over for each
+file that changes; see 'watch'.
+ */
+fn run(args: &Arguments, out: &mut files::Printer) -> Status {
+    if args.watch {
+        return watch(args, out);
+    }
+    let mut report = Report::new(args.dry_run);
+    let inputs = input_paths(args, &args.inputs);
+    let status = replace_everywhere(args, out, &mut report, inputs, &mut watch::Written::default());
+    if args.json {
+        out.print(&(report.to_json() + "\n"));
+    }
+    status
+}
+
+/*
+Replace in each of the files in 'inputs', which also has a message for each
+input that couldn't be gone through; see 'input_paths'. What's written is
+noted in 'written' for --watch.
+ */
+fn replace_everywhere(args: &Arguments, out: &mut files::Printer, report: &mut Report,
+                      (paths, errors): (Vec<PathBuf>, Vec<String>), written: &mut watch::Written) -> Status {
+    let rules = match load_rules(args) {
+        Ok(rules) => rules,
+        Err((status, e)) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return report.fail(status, e);
+        }
+    };
+    let search = match compile(args, &rules) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return report.fail(Status::BadPattern, e);
+        }
+    };
+    let scope = match Scope::new(&args.scope) {
+        Ok(scope) => scope,
         Err(e) => {
-            eprintln!("{} failed to read from file '{}': {:?}",
-            "Error:".red().bold(), args.filename, e);
-            std::process::exit(1);
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return report.fail(Status::BadPattern, e);
+        }
+    };
+
+    let mut journal = if args.journaling() {
+        match journal::directory() {
+            Some(directory) => Some(journal::Journal::new(&directory, &env::args().collect::<Vec<_>>())),
+            None => {
+                let e = "no directory for the undo journal: set QUICKREPLACE_JOURNAL or HOME, or use --no-journal";
+                eprintln!("{} {}", "Error:".red().bold(), e);
+                return report.fail(Status::Failed, e.to_string());
+            }
+        }
+    } else {
+        None
+    };
+
+    for e in errors {
+        eprintln!("{} {}", "Error:".red().bold(), e);
+        report.errors.push(e);
+    }
+    let mut done = 0;
+    let (mut rule_matches, mut rule_files) = (vec![0; rules.len()], vec![0; rules.len()]);
+
+    let report_file = |out: &mut files::Printer, path: &Path, outcome: Result<Outcome, String>| {
+        let name = path.display().to_string();
+        let mut outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(message) => {
+                eprintln!("{} {}", "Error:".red().bold(), message);
+                report.files.push(FileReport::failed(name, message.clone()));
+                report.errors.push(message);
+                return;
+            }
+        };
+
+        let mut file = match &outcome.counts {
+            Some(counts) => FileReport::counted(name, outcome.changed, counts.iter().sum()),
+            None if outcome.members.is_empty() => FileReport::new(name, outcome.changed, &outcome.matches),
+            None => FileReport::archive(name, outcome.changed, &outcome.members),
+        };
+        if !args.json {
+            out.print(&outcome.shown);
+        }
+        if let (Some(journal), Some(entry)) = (&mut journal, &mut outcome.entry) {
+            if let Err(e) = journal.record(entry) {
+                let message = format!("changed '{}' but failed to record it in the undo journal: {:?}", file.path, e);
+                eprintln!("{} {}", "Error:".red().bold(), message);
+                file.error = Some(message.clone());
+                report.errors.push(message);
+            }
+        }
+        if let Some(entry) = &outcome.entry {
+            written.note(&entry.path, entry.replaced);
+            if args.backup {
+                written.note(&files::backup_path(&entry.path), entry.original);
+            }
+        }
+        let counts = outcome.counts.unwrap_or_else(|| outcome.matches.iter().map(Vec::len).collect());
+        for (i, count) in counts.into_iter().enumerate() {
+            rule_matches[i] += count;
+            rule_files[i] += (count > 0) as usize;
+        }
+        if args.dry_run && outcome.changed && !args.json {
+            out.print(&format!("{}: {} {}\n\n", file.path.bold(), file.matches,
+                               if file.matches == 1 { "match" } else { "matches" }));
+        }
+
+        report.matches += file.matches;
+        report.files_changed += outcome.changed as usize;
+        done += file.error.is_none() as usize;
+        if file.matches > 0 || file.error.is_some() {
+            report.files.push(file);
         }
     };
+    // The questions have to come one at a time.
+    if args.interactive || args.jobs == 1 {
+        replace_serially(args, &search, scope.as_ref(), &paths, out, report_file);
+    } else {
+        replace_in_parallel(args, &search, scope.as_ref(), &paths, out, report_file);
+    }
+
+    if args.dry_run && !args.json {
+        out.print(&format!("{} {} in {} {} (dry run, nothing written)\n", report.matches,
+                           if report.matches == 1 { "match" } else { "matches" }, report.files_changed,
+                           if report.files_changed == 1 { "file" } else { "files" }));
+    }
+    // On stderr, since stdout may be the replaced text.
+    if args.rules_file.is_some() {
+        eprint!("{}", rules::summary(&rules, &rule_matches, &rule_files));
+    }
+
+    report.finish(done)
+}
+
+// The files that 'roots', all or some of the inputs, come to, and a message for each that couldn't be gone through.
+fn input_paths(args: &Arguments, roots: &[String]) -> (Vec<PathBuf>, Vec<String>) {
+    if args.recursive {
+        files::walk(roots, &args.walk)
+    } else if args.in_place || args.search.is_some() {
+        files::expand_inputs(roots)
+    } else {
+        (vec![PathBuf::from(&roots[0])], Vec::new())
+    }
+}
 
-    let replaced_data = match replace(&args.target, &args.replacement, &data) {
-        Ok(v) => v,
+/*
+--watch: replace everywhere as usual, then wait for files to change and
+replace in those of them the arguments name, over and over until
+interrupted. Each round is a run of its own, with its own journal, so
+'undo' takes back the last one. Only returns if the first round couldn't
+get going at all, with its status.
+ */
+fn watch(args: &Arguments, out: &mut files::Printer) -> Status {
+    let watcher = match watch::Watcher::new(&watched_directories(args)) {
+        Ok(watcher) => watcher,
         Err(e) => {
-            eprintln!("{} failed to replace text: {:?}",
-            "Error:".red().bold(),e);
-            std::process::exit(1);
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return Status::Failed;
+        }
+    };
+    let mut written = watch::Written::default();
+    let mut report = Report::new(args.dry_run);
+    let (paths, errors) = input_paths(args, &args.inputs);
+    let mut inputs = watch::Inputs::default();
+    inputs.add(&paths);
+    let status = replace_everywhere(args, out, &mut report, (paths, errors), &mut written);
+    if matches!(status, Status::Usage | Status::BadPattern) || (status == Status::Failed && report.files.is_empty()) {
+        return status;
+    }
+    show_replaced(args, out, &report);
+
+    // With --recursive, which roots to walk again for a new file; one that wasn't there may be by now.
+    let roots: Vec<(&String, Option<PathBuf>)> = args.inputs.iter().map(|root| (root, fs::canonicalize(root).ok()))
+        .collect();
+    eprintln!("Watching for changes; press Ctrl-C to stop.");
+    loop {
+        let (mut changed, errors) = watcher.changes();
+        for e in errors {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+        }
+        changed.retain(|path| !written.is_ours(path));
+        // Only a new file needs the inputs gone through again to see whether it's one of them.
+        let new: Vec<&PathBuf> = changed.iter().filter(|path| !inputs.knows(path)).collect();
+        if !new.is_empty() {
+            let again: Vec<String> = roots.iter()
+                .filter(|(_, root)| !args.recursive || root.as_ref().is_none_or(|root| {
+                    new.iter().any(|path| path.starts_with(root))
+                }))
+                .map(|(root, _)| root.to_string())
+                .collect();
+            // The errors were reported in the first round.
+            inputs.add(&input_paths(args, &again).0);
+        }
+        let paths = inputs.named(&changed);
+        if paths.is_empty() {
+            continue;
         }
+        let mut report = Report::new(args.dry_run);
+        replace_everywhere(args, out, &mut report, (paths, Vec::new()), &mut written);
+        show_replaced(args, out, &report);
+    }
+}
+
+// The directories to watch for the files the arguments name, and whether to watch what's under them too.
+fn watched_directories(args: &Arguments) -> Vec<(PathBuf, bool)> {
+    let parent = |path: &Path| match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => (parent.to_path_buf(), false),
+        _ => (PathBuf::from("."), false),
     };
+    let (paths, _) = files::expand_inputs(&args.inputs);
+    let mut directories: Vec<(PathBuf, bool)> = Vec::new();
+    for path in paths {
+        if args.recursive && path.is_dir() {
+            directories.push((path, true));
+            continue;
+        }
+        directories.push(parent(&path));
+        // A symlink is rewritten through to its target, so edits to the target have to be seen too.
+        if let Ok(target) = fs::canonicalize(&path) {
+            if target.parent() != fs::canonicalize(parent(&path).0).ok().as_deref() {
+                directories.push(parent(&target));
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    directories.retain(|directory| seen.insert(directory.clone()));
+    directories
+}
 
-    match fs::write(&args.output, &replaced_data){
-        Ok(_) => {},
+// With --watch, which files a round rewrote, since nothing else says; --dry-run shows its diffs already.
+fn show_replaced(args: &Arguments, out: &mut files::Printer, report: &Report) {
+    if args.dry_run {
+        return;
+    }
+    for file in report.files.iter().filter(|file| file.changed) {
+        out.print(&format!("{}: {} {} replaced\n", file.path.bold(), file.matches,
+                           if file.matches == 1 { "match" } else { "matches" }));
+    }
+}
+
+fn replace_serially(args: &Arguments, search: &Search, scope: Option<&Scope>, paths: &[PathBuf],
+                    out: &mut files::Printer,
+                    mut report: impl FnMut(&mut files::Printer, &Path, Result<Outcome, String>)) {
+    let mut session = interactive::Session::default();
+    for path in paths {
+        if session.finished() {
+            break;
+        }
+        let outcome = replace_file(args, search, scope, &mut session, Some(out), path);
+        report(out, path, outcome);
+    }
+}
+
+/*
+Hand the files out to a pool of 'args.jobs' worker threads, which share the
+compiled rules. Each worker sends back what came of a file along with its
+index, and 'report' is called for the files strictly in order, holding on to
+any that finish early, so the output and the journal are the same however
+the work was split. A file that fails is just reported like any other.
+ */
+fn replace_in_parallel(args: &Arguments, search: &Search, scope: Option<&Scope>, paths: &[PathBuf],
+                       out: &mut files::Printer,
+                       mut report: impl FnMut(&mut files::Printer, &Path, Result<Outcome, String>)) {
+    let (index_sender, index_receiver) = channel::unbounded();
+    let (done_sender, done_receiver) = channel::unbounded();
+    for i in 0..paths.len() {
+        index_sender.send(i).unwrap();
+    }
+    drop(index_sender);
+
+    crossbeam::scope(|spawner| {
+        for _ in 0..args.jobs.min(paths.len()) {
+            let index_receiver = index_receiver.clone();
+            let done_sender = done_sender.clone();
+            spawner.spawn(move |_| {
+                // Never asked anything, since this isn't --interactive.
+                let mut session = interactive::Session::default();
+                for i in index_receiver {
+                    done_sender.send((i, replace_file(args, search, scope, &mut session, None, &paths[i]))).unwrap();
+                }
+            });
+        }
+        // Only the workers' clones should keep the channel open.
+        drop(done_sender);
+
+        let mut early = HashMap::new();
+        let mut next = 0;
+        for (i, outcome) in done_receiver {
+            early.insert(i, outcome);
+            while let Some(outcome) = early.remove(&next) {
+                report(out, &paths[next], outcome);
+                next += 1;
+            }
+        }
+    }).unwrap();
+}
+
+// 'live' is where to print what's shown for the file as it comes, if it needn't wait its turn.
+fn replace_file(args: &Arguments, search: &Search, scope: Option<&Scope>, session: &mut interactive::Session,
+                live: Option<&mut files::Printer>, path: &Path) -> Result<Outcome, String> {
+    if let Some(options) = &args.search {
+        return search_file(args, search, scope, options, path);
+    }
+    let kind = archive::Kind::of(path);
+    if let (Search::Bytes(rules), true, None) = (search, args.stream, kind) {
+        return replace_stream(args, rules, scope, live, path);
+    }
+
+    let raw = files::read(path)
+        .map_err(|e| format!("failed to read from file '{}': {:?}", path.display(), e))?;
+    let (replaced, members) = match kind {
+        Some(kind) => replace_archive(args, search, scope, session, path, kind, &raw)?,
+        None => (replace_contents(args, search, scope, session, path, &raw)?, Vec::new()),
+    };
+    if !args.dry_run {
+        write_result(args, path, &replaced.data, replaced.changed)?;
+    }
+    let entry = (replaced.changed && args.entries()).then(|| journal::Entry::between(path, &raw, &replaced.data));
+    Ok(Outcome { matches: replaced.matches, counts: None, changed: replaced.changed, entry, shown: replaced.diff,
+                 members })
+}
+
+// What replacing in the contents of a file, or of a member of an archive, came to.
+struct Replaced {
+    data: Vec<u8>,
+    matches: Vec<Vec<(u64, u64)>>,
+    changed: bool,
+    // With --dry-run, the diff.
+    diff: String,
+}
+
+// Replace in 'raw', the contents of 'path'.
+fn replace_contents(args: &Arguments, search: &Search, scope: Option<&Scope>, session: &mut interactive::Session,
+                    path: &Path, raw: &[u8]) -> Result<Replaced, String> {
+    match search {
+        Search::Text(rules) => replace_text(args, rules, scope, session, path, raw),
+        Search::Bytes(rules) => replace_bytes(args, rules, scope, path, raw),
+    }
+}
+
+/*
+Replace in each member of the archive 'raw' as if it were a file of its own;
+see 'archive'. The matches are those of every member together, and the
+members that had any are returned with theirs.
+ */
+fn replace_archive(args: &Arguments, search: &Search, scope: Option<&Scope>, session: &mut interactive::Session,
+                   path: &Path, kind: archive::Kind,
+                   raw: &[u8]) -> Result<(Replaced, Members), String> {
+    let mut replaced = Replaced { data: Vec::new(), matches: Vec::new(), changed: false, diff: String::new() };
+    let mut members = Vec::new();
+    let rewritten = archive::rewrite(kind, path, raw, |name, contents| {
+        let member = replace_contents(args, search, scope, session, &member_path(path, name), contents)?;
+        replaced.matches.resize(member.matches.len(), Vec::new());
+        for (all, spans) in replaced.matches.iter_mut().zip(&member.matches) {
+            all.extend(spans);
+        }
+        replaced.diff += &member.diff;
+        if member.matches.iter().any(|spans| !spans.is_empty()) {
+            members.push((name.to_string(), member.matches));
+        }
+        Ok(member.changed.then_some(member.data))
+    })?;
+    replaced.changed = rewritten.is_some();
+    replaced.data = rewritten.unwrap_or_else(|| raw.to_vec());
+    Ok((replaced, members))
+}
+
+// What the member 'name' of the archive 'path' is called in messages.
+fn member_path(path: &Path, name: &str) -> PathBuf {
+    PathBuf::from(format!("{}:{}", path.display(), name))
+}
+
+// 'search': find the matches, as 'replace_text' or 'replace_bytes' would, and list them.
+fn search_file(args: &Arguments, search: &Search, scope: Option<&Scope>, options: &search::SearchOptions,
+               path: &Path) -> Result<Outcome, String> {
+    let raw = files::read(path).map_err(|e| format!("failed to read from file '{}': {:?}", path.display(), e))?;
+    let kind = match archive::Kind::of(path) {
+        Some(kind) => kind,
+        None => {
+            let name = if files::is_stdio(path) { "(standard input)".to_string() } else { path.display().to_string() };
+            let (matches, shown) = search_contents(args, search, scope, options, path, &name, &raw)?;
+            let outcome = Outcome { matches: vec![matches], counts: None, changed: false, entry: None, shown,
+                                   members: Vec::new() };
+            return Ok(outcome);
+        }
+    };
+
+    let (mut matches, mut shown, mut members) = (Vec::new(), String::new(), Vec::new());
+    archive::rewrite(kind, path, &raw, |name, contents| {
+        let member = member_path(path, name);
+        let (spans, member_shown) = search_contents(args, search, scope, options, &member,
+                                                    &member.display().to_string(), contents)?;
+        matches.extend(&spans);
+        shown += &member_shown;
+        if !spans.is_empty() {
+            members.push((name.to_string(), vec![spans]));
+        }
+        Ok(None)
+    })?;
+    Ok(Outcome { matches: vec![matches], counts: None, changed: false, entry: None, shown, members })
+}
+
+// The matches in 'raw', the contents of 'path', and what to show for them under 'name'.
+fn search_contents(args: &Arguments, search: &Search, scope: Option<&Scope>, options: &search::SearchOptions,
+                   path: &Path, name: &str, raw: &[u8]) -> Result<(Vec<(u64, u64)>, String), String> {
+    let in_scope = |text: &[u8], spans: matcher::Spans| match scope {
+        Some(scope) => {
+            let regions = scope.regions(text, path)?;
+            Ok(spans.into_iter().filter(|&(start, end)| regions.contains(start, end)).collect())
+        }
+        None => Ok::<_, String>(spans),
+    };
+    let (data, encoding) = match search {
+        Search::Text(_) => match decode(args, path, raw)? {
+            (Cow::Borrowed(text), encoding) => (Cow::Borrowed(text.as_bytes()), Some(encoding)),
+            (Cow::Owned(text), encoding) => (Cow::Owned(text.into_bytes()), Some(encoding)),
+        },
+        Search::Bytes(_) => (Cow::Borrowed(raw), None),
+    };
+    let (bom, text) = endings::split_bom(&data);
+    let spans = in_scope(text, match search {
+        Search::Text(rules) => rules[0].0.find_iter(std::str::from_utf8(text).expect("decoded")),
+        Search::Bytes(rules) => rules[0].0.find_iter(text),
+    })?;
+    let matches = match encoding {
+        Some(encoding) => {
+            let data = std::str::from_utf8(&data).expect("decoded");
+            text_offsets(std::slice::from_ref(&spans), data, bom.len(), encoding).remove(0)
+        }
+        None => offsets(&spans, bom.len()),
+    };
+    Ok((matches, search::show(name, text, &spans, options)))
+}
+
+// Run with the command line 'list', returning the status and everything printed to stdout.
+#[cfg(test)]
+fn run_captured(list: &[&str]) -> (Status, String) {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let args = parse_arg_list(&list.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap();
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let status = run(&args, &mut files::Printer::new(Box::new(Shared(printed.clone()))));
+    let printed = String::from_utf8(printed.take()).unwrap();
+    (status, printed)
+}
+
+#[test]
+fn test_jobs() {
+    let dir = env::temp_dir().join(format!("quickreplace-jobs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Of very different sizes, so the workers finish them out of order.
+    for i in 0..40 {
+        let lines = if i % 7 == 0 { 300 } else { i % 5 };
+        let text: String = (0..lines).map(|n| format!("foo {} {}\n", i, n)).collect();
+        fs::write(dir.join(format!("{:02}.txt", i)), text).unwrap();
+    }
+    let root = dir.to_str().unwrap();
+
+    let commands = [&["-n", "-r", "foo", "bar"][..], &["-n", "-r", "--json", "foo", "bar"],
+                    &["search", "-r", "-c", "foo"]];
+    for command in commands {
+        let serial = run_captured(&[command, &["--jobs", "1", root]].concat());
+        assert_eq!(run_captured(&[command, &["--jobs", "8", root]].concat()), serial);
+    }
+    let (status, printed) = run_captured(&["search", "-r", "-l", "--jobs", "8", "foo", root]);
+    let names: Vec<String> = (0..40).filter(|i| i % 5 != 0 || i % 7 == 0)
+        .map(|i| format!("{}\n", dir.join(format!("{:02}.txt", i)).display())).collect();
+    assert_eq!((status, printed), (Status::Done, names.concat()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_search_closed_pipe() {
+    use std::io::{self, Write};
+    // Takes so many bytes, then fails as a pipe does once 'head' has had enough.
+    struct Closing(usize);
+    impl Write for Closing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let dir = env::temp_dir().join(format!("quickreplace-pipe-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("big.txt");
+    fs::write(&path, (0..1000).map(|i| format!("foo {}\n", i)).collect::<String>()).unwrap();
+    let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let search = parse_arg_list(&args(&["search", "foo", path.to_str().unwrap()])).unwrap();
+    assert_eq!(run(&search, &mut files::Printer::new(Box::new(Closing(100)))), Status::Done);
+    let dry_run = parse_arg_list(&args(&["-n", "-i", "foo", "bar", path.to_str().unwrap()])).unwrap();
+    assert_eq!(run(&dry_run, &mut files::Printer::new(Box::new(Closing(0)))), Status::Done);
+    assert!(fs::read_to_string(&path).unwrap().starts_with("foo 0\n"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_json_offsets() {
+    let dir = env::temp_dir().join(format!("quickreplace-offsets-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rules = dir.join("rules.txt");
+    // The third rule matches across what the first two put in.
+    fs::write(&rules, "s/foo/xx/\ns/bar/q/\ns/xx q/Z/\n").unwrap();
+    let latin1 = dir.join("latin1.txt");
+    fs::write(&latin1, b"caf\xe9 foo bar\n").unwrap();
+    let utf16 = dir.join("utf16.txt");
+    let text: Vec<u8> = "\u{feff}\u{e9} foo bar\n".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    fs::write(&utf16, text).unwrap();
+    let offsets = |json: &serde_json::Value| json["files"][0]["offsets"].as_array().unwrap().iter()
+        .map(|offset| (offset["rule"].as_u64().unwrap(), offset["start"].as_u64().unwrap(),
+                       offset["end"].as_u64().unwrap()))
+        .collect::<Vec<_>>();
+
+    let (status, printed) = run_captured(&["--json", "-n", "--encoding", "latin1", "-f", rules.to_str().unwrap(),
+                                           latin1.to_str().unwrap()]);
+    assert_eq!(status, Status::Done);
+    let json: serde_json::Value = serde_json::from_str(&printed).unwrap();
+    assert_eq!(offsets(&json), vec![(1, 5, 8), (2, 9, 12), (3, 5, 12)]);
+
+    // Offsets in the UTF-16 file itself, byte order mark and all.
+    let (status, printed) = run_captured(&["--json", "-n", "-f", rules.to_str().unwrap(), utf16.to_str().unwrap()]);
+    assert_eq!(status, Status::Done);
+    let json: serde_json::Value = serde_json::from_str(&printed).unwrap();
+    assert_eq!(offsets(&json), vec![(1, 6, 12), (2, 14, 20), (3, 6, 20)]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_binary_left_alone() {
+    let dir = env::temp_dir().join(format!("quickreplace-binary-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (image, text) = (dir.join("a.png"), dir.join("b.txt"));
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR foo \xe9\0";
+    fs::write(&image, png).unwrap();
+    fs::write(&text, "foo\n").unwrap();
+
+    let (status, _) = run_captured(&["-i", "--no-journal", "foo", "bar", image.to_str().unwrap(),
+                                     text.to_str().unwrap()]);
+    assert_eq!(status, Status::PartlyFailed);
+    assert_eq!(fs::read(&image).unwrap(), png);
+    assert_eq!(fs::read_to_string(&text).unwrap(), "bar\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watched_directories() {
+    let dir = env::temp_dir().join(format!("quickreplace-watched-{}", std::process::id()));
+    let (inputs, targets) = (dir.join("inputs"), dir.join("targets"));
+    fs::create_dir_all(&inputs).unwrap();
+    fs::create_dir_all(&targets).unwrap();
+    let targets = fs::canonicalize(&targets).unwrap();
+    fs::write(inputs.join("a.txt"), "").unwrap();
+    fs::write(targets.join("b.txt"), "").unwrap();
+    std::os::unix::fs::symlink(targets.join("b.txt"), inputs.join("b.txt")).unwrap();
+
+    let watch = |input: &Path| {
+        let args = ["-i", "--watch", "a", "b", &input.to_string_lossy()].map(String::from);
+        watched_directories(&parse_arg_list(&args).unwrap())
+    };
+    assert_eq!(watch(&inputs.join("a.txt")), vec![(inputs.clone(), false)]);
+    assert_eq!(watch(&inputs.join("b.txt")), vec![(inputs.clone(), false), (targets, false)]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// The rules in the --rules file, or the one rule given by <target> and <replacement>.
+fn load_rules(args: &Arguments) -> Result<Vec<Rule>, (Status, String)> {
+    match &args.rules_file {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| (Status::Failed, format!("failed to read rules file '{}': {:?}", path, e)))?;
+            rules::parse_rules_file(path, &text, &args.matching).map_err(|e| (Status::BadPattern, e))
+        }
+        None => Ok(vec![Rule {
+            target: args.target.clone(),
+            replacement: args.replacement.clone(),
+            options: args.matching.clone(),
+            line: 0,
+        }]),
+    }
+}
+
+fn compile(args: &Arguments, rules: &[Rule]) -> Result<Search, String> {
+    let error = |rule: &Rule, e: String| match rule.line {
+        0 => format!("failed to replace text: {}", e),
+        line => format!("invalid rule on line {}: {}", line, e),
+    };
+    let regex_error = |rule: &Rule, e: regex::Error| {
+        let hint = match rule.line {
+            0 => "use -F to match <target> as plain text",
+            _ => "use the F flag to match the target as plain text",
+        };
+        error(rule, format!("invalid regex: {}\n{}", matcher::describe_error(&rule.target, &rule.options, &e), hint))
+    };
+    let template = |rule: &Rule| Template::parse(&rule.replacement, rule.options.fixed_strings)
+        .map_err(|e| error(rule, e));
+    if args.stream || args.bytes {
+        rules.iter()
+            .map(|rule| Ok((ByteMatcher::new(&rule.target, &rule.options)
+                .map_err(|e| regex_error(rule, e))?, template(rule)?)))
+            .collect::<Result<_, _>>()
+            .map(Search::Bytes)
+    } else {
+        rules.iter()
+            .map(|rule| Ok((Matcher::new(&rule.target, &rule.options)
+                .map_err(|e| regex_error(rule, e))?, template(rule)?)))
+            .collect::<Result<_, _>>()
+            .map(Search::Text)
+    }
+}
+
+fn replace_text(args: &Arguments, rules: &[(Matcher, Template)], scope: Option<&Scope>,
+                session: &mut interactive::Session, path: &Path, raw: &[u8]) -> Result<Replaced, String> {
+    let (data, encoding) = decode(args, path, raw)?;
+    // Any byte order mark is left out of the matching; see 'endings'.
+    let (bom, text) = data.split_at(endings::split_bom(data.as_bytes()).0.len());
+
+    let mut replaced_data = text.to_string();
+    let mut found = Vec::new();
+    let mut trail = trail::Trail::default();
+    for (matcher, replacement) in rules {
+        // Earlier rules may have moved lines about, so find the regions afresh each time.
+        let regions = scope.map(|scope| scope.regions(replaced_data.as_bytes(), path)).transpose()?;
+        let replaced = if args.interactive {
+            session.replace(&path.to_string_lossy(), &replaced_data, matcher, replacement, regions.as_ref(),
+                            interactive::ask_terminal)
+        } else if let Some(regions) = &regions {
+            matcher.replace_within(&replaced_data, replacement, regions)
+        } else {
+            matcher.replace_all(&replaced_data, replacement)
+        };
+        let (replaced, replacements) = replaced
+            .map_err(|e| format!("failed to replace in file '{}': {}", path.display(), e))?;
+        replaced_data = replaced;
+        found.push(replacements.iter().map(|&(start, end, _)| trail.back(start, end)).collect());
+        trail.push(&replacements);
+    }
+    let matches = text_offsets(&found, &data, bom.len(), encoding);
+    let replaced_data = bom.to_string() + &endings::keep_line_endings_in(text, replaced_data);
+
+    let changed = replaced_data != data;
+    let replaced_raw = encoding::encode(&replaced_data, encoding)
+        .map_err(|e| format!("failed to replace in file '{}': {}", path.display(), e))?;
+    let diff = if args.dry_run && changed {
+        diff::unified_diff(&path.to_string_lossy(), &data, &replaced_data)
+    } else {
+        String::new()
+    };
+    Ok(Replaced { data: replaced_raw.into_owned(), matches, changed, diff })
+}
+
+// The text of the file 'path', whose contents are 'raw', and the encoding it was in.
+fn decode<'r>(args: &Arguments, path: &Path,
+              raw: &'r [u8]) -> Result<(Cow<'r, str>, &'static encoding_rs::Encoding), String> {
+    encoding::decode(raw, args.encoding).map_err(|e| format!("failed to read from file '{}': {}", path.display(), e))
+}
+
+// Like 'replace_text', for --bytes: the diff shows invalid UTF-8 as U+FFFD.
+fn replace_bytes(args: &Arguments, rules: &[(ByteMatcher, Template)], scope: Option<&Scope>,
+                 path: &Path, data: &[u8]) -> Result<Replaced, String> {
+    let (bom, text) = endings::split_bom(data);
+
+    let mut replaced_data = text.to_vec();
+    let mut matches = Vec::new();
+    let mut trail = trail::Trail::default();
+    for (matcher, replacement) in rules {
+        let replaced = match scope {
+            Some(scope) => matcher.replace_within(&replaced_data, replacement, &scope.regions(&replaced_data, path)?),
+            None => matcher.replace_all(&replaced_data, replacement, 0),
+        };
+        let (replaced, replacements) = replaced
+            .map_err(|e| format!("failed to replace in file '{}': {}", path.display(), e))?;
+        replaced_data = replaced;
+        let spans: matcher::Spans = replacements.iter().map(|&(start, end, _)| trail.back(start, end)).collect();
+        matches.push(offsets(&spans, bom.len()));
+        trail.push(&replacements);
+    }
+    let replaced_data = [bom, &endings::keep_line_endings(text, replaced_data)].concat();
+
+    let changed = replaced_data != data;
+    let diff = if args.dry_run && changed {
+        diff::unified_diff(&path.to_string_lossy(), &String::from_utf8_lossy(data),
+                           &String::from_utf8_lossy(&replaced_data))
+    } else {
+        String::new()
+    };
+    Ok(Replaced { data: replaced_data, matches, changed, diff })
+}
+
+// The 'spans' in a file's text as offsets in the file, which has 'skipped' bytes before the text.
+fn offsets(spans: &[(usize, usize)], skipped: usize) -> Vec<(u64, u64)> {
+    spans.iter().map(|&(start, end)| ((skipped + start) as u64, (skipped + end) as u64)).collect()
+}
+
+/*
+Like 'offsets', for the spans each rule 'found' in a file decoded from
+'encoding': 'data' is all of its text, with the 'skipped' bytes of any byte
+order mark, and the offsets are of the bytes in the file.
+ */
+fn text_offsets(found: &[matcher::Spans], data: &str, skipped: usize,
+                encoding: &'static encoding_rs::Encoding) -> Vec<Vec<(u64, u64)>> {
+    let mut ends: Vec<usize> = found.iter().flatten().flat_map(|&(start, end)| [skipped + start, skipped + end])
+        .collect();
+    ends.sort_unstable();
+    ends.dedup();
+    let encoded = encoding::encoded_offsets(data, encoding, &ends);
+    let in_file = |offset| encoded[ends.binary_search(&(skipped + offset)).expect("one of the ends")] as u64;
+    found.iter().map(|spans| spans.iter().map(|&(start, end)| (in_file(start), in_file(end))).collect()).collect()
+}
+
+fn write_result(args: &Arguments, path: &Path, data: &[u8], changed: bool) -> Result<(), String> {
+    let written = match &args.output {
+        Some(output) => files::ignore_broken_pipe(files::write_output(output, data))
+            .map_err(|e| (output.clone(), e)),
+        // Leave untouched files alone, timestamps included.
+        None if !changed => Ok(()),
+        None => files::write_atomically(path, data, args.rewrite())
+            .map_err(|e| (path.display().to_string(), e)),
+    };
+    written.map_err(|(name, e)| format!("failed to write to file '{}': {:?}", name, e))
+}
+
+/*
+--stream: the replaced lines go straight to the output file, or to a
+temporary file that replaces the original once it's complete. With
+--dry-run each changed line is shown as a hunk of its own, printed to
+'live' straight away when there is one. The input and output are hashed on
+the way through for the undo journal, and what each changed line was is
+spilled to a file rather than kept; see 'journal'. Only --json needs where
+each match was, so otherwise they're just counted, and nothing grows with
+the size of the file.
+ */
+fn replace_stream(args: &Arguments, rules: &[(ByteMatcher, Template)], scope: Option<&Scope>,
+                  mut live: Option<&mut files::Printer>, path: &Path) -> Result<Outcome, String> {
+    let mut input = files::open(path).map(journal::Hashing::new)
+        .map_err(|e| format!("failed to read from file '{}': {:?}", path.display(), e))?;
+
+    let name = path.to_string_lossy();
+    let mut diff = String::new();
+    let mut shown = false;
+    let mut spill = None;
+    let show = |change: &stream::Change| {
+        if args.journaling() {
+            let spill = match &mut spill {
+                Some(spill) => spill,
+                None => {
+                    let directory = journal::directory().ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::NotFound, "no directory for the undo journal")
+                    })?;
+                    spill.insert(journal::Spill::new(&directory)?)
+                }
+            };
+            spill.push(change.offset, change.new.len() as u64, change.old)?;
+        }
+        if !args.dry_run || args.json {
+            return Ok(());
+        }
+        let mut hunk = if shown { String::new() } else { diff::header(&name) };
+        hunk += &diff::line_hunk(change.number, &String::from_utf8_lossy(change.old),
+                                 &String::from_utf8_lossy(change.new));
+        shown = true;
+        match &mut live {
+            Some(out) => out.print(&hunk),
+            None => diff += &hunk,
+        }
+        Ok(())
+    };
+    let utf8 = !args.bytes;
+
+    let mut found = stream::Found::new(args.json);
+    let mut replaced = 0;
+    let result = match &args.output {
+        _ if args.dry_run => stream::replace_lines(&mut input, std::io::sink(), rules, scope, utf8, &mut found, show),
+        Some(output) => {
+            // Creating the output would empty the input before we'd read it.
+            if same_file(path, Path::new(output)) {
+                return Err(format!("'{}' is both input and output; use --in-place to rewrite it", output));
+            }
+            // Only once the matches so far are in, since it's no error for stdout's reader to go.
+            let written = files::create(output)
+                .and_then(|file| stream::replace_lines(&mut input, file, rules, scope, utf8, &mut found, show));
+            files::ignore_broken_pipe(written)
+        }
+        None => files::rewrite_atomically(path, args.rewrite(), |file| {
+            let mut output = journal::Hashing::new(file);
+            stream::replace_lines(&mut input, &mut output, rules, scope, utf8, &mut found, show)?;
+            replaced = output.hash();
+            Ok(found.any())
+        }),
+    };
+    result.map_err(|e| format!("failed to replace in file '{}': {:?}", path.display(), e))?;
+    let changed = found.any();
+    let entry = (changed && args.entries()).then(|| journal::Entry {
+        path: path.to_path_buf(),
+        original: input.hash(),
+        replaced,
+        hunks: spill.map_or(journal::Hunks::Kept(Vec::new()), journal::Hunks::Spilled),
+    });
+    let (matches, counts) = match found.spans {
+        Some(spans) => (spans, None),
+        None => (Vec::new(), Some(found.counts)),
+    };
+    Ok(Outcome { matches, counts, changed, entry, shown: diff, members: Vec::new() })
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    if files::is_stdio(a) || files::is_stdio(b) {
+        return false;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/*
+'quickreplace undo [N]': put back the files the last N runs, 1 by default,
+rewrote in place, newest run first; see 'journal'. A run is only undone if
+none of its files have changed since, and undoing stops at the first run
+that can't be, which counts as partly failing if some runs were undone.
+ */
+fn undo(args: &[String], out: &mut files::Printer) -> Status {
+    let count = match args {
+        [] => Ok(1),
+        [count] => count.parse::<usize>().ok().filter(|&n| n > 0)
+            .ok_or_else(|| format!("expected a number of runs to undo, got '{}'", count)),
+        _ => Err(format!("undo takes at most one argument, the number of runs, got {}", args.len())),
+    };
+    let count = match count {
+        Ok(count) => count,
+        Err(message) => {
+            print_usage();
+            eprintln!("{} {}", "Error:".red().bold(), message);
+            return Status::Usage;
+        }
+    };
+    let runs = match journal::directory().ok_or_else(|| "no undo journal: neither QUICKREPLACE_JOURNAL nor HOME is set"
+        .to_string()).and_then(|directory| journal::runs(&directory)) {
+        Ok(runs) if runs.is_empty() => {
+            eprintln!("{} nothing to undo", "Error:".red().bold());
+            return Status::NoMatches;
+        }
+        Ok(runs) => runs,
         Err(e) => {
-            eprintln!("{} failed to write to file '{}': {:?}",
-            "Error:".red().bold(), args.filename, e);
-            std::process::exit(1);
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return Status::Failed;
         }
     };
+
+    for (undone, path) in runs.iter().take(count).enumerate() {
+        let run = match journal::read_run(path).and_then(|run| journal::check(&run).map(|()| run)) {
+            Ok(run) => run,
+            Err(e) => {
+                eprintln!("{} can't undo '{}': {}", "Error:".red().bold(), path.display(), e);
+                return undo_failed(undone);
+            }
+        };
+        // Newest first, for a file the run changed twice.
+        for entry in run.entries.iter().rev() {
+            if let Err(e) = journal::restore(entry) {
+                eprintln!("{} {}", "Error:".red().bold(), e);
+                return undo_failed(undone);
+            }
+        }
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("{} failed to remove journal '{}': {:?}", "Error:".red().bold(), path.display(), e);
+            return undo_failed(undone);
+        }
+        let files = run.entries.len();
+        out.print(&format!("undid {}: {} {} restored\n", run.command.bold(), files,
+                           if files == 1 { "file" } else { "files" }));
+    }
+    if runs.len() < count {
+        out.print(&format!("only {} {} to undo\n", runs.len(), if runs.len() == 1 { "run was" } else { "runs were" }));
+    }
+    Status::Done
+}
+
+// Whether undoing went wrong from the start or after some runs were undone.
+fn undo_failed(undone: usize) -> Status {
+    if undone == 0 { Status::Failed } else { Status::PartlyFailed }
 }
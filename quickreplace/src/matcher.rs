@@ -0,0 +1,398 @@
+use regex::{bytes, Regex, RegexBuilder};
+
+use crate::scope::Regions;
+use crate::template::{Group, Template};
+
+/*
+Finding what to replace
+
+Everything after argument parsing works with a Matcher rather than a Regex,
+so the choice of engine is made once, here:
+
+- Regex: <target> is a regular expression and <replacement> is a template
+  that may refer to capture groups as $1 or $name; see 'template'.
+
+- Literal (--fixed-strings): <target> is plain text, so 'a.b' or 'foo(' need
+  no escaping, and <replacement> is inserted as-is, '$' included. Plain
+  substring search does the work; only a case-insensitive literal search
+  falls back to an escaped regex, since case folding is the regex crate's job.
+
+--word-regexp only accepts matches with no word character (a letter, digit
+or '_') right before or right after them, as 'grep -w' does. That's not
+quite '\b': 'foo(' is a whole word in 'foo()', though there's no boundary
+after the '('. --ignore-case matches regardless of case.
+
+Three more save remembering inline flags for patterns that span lines, and
+do nothing for plain text:
+
+- --multiline ('(?m)'): '^' and '$' match at the start and end of every
+  line, not just of the file.
+- --dot-all ('(?s)'): '.' matches '\n' too, so 'fn a\(\).*?\n}\n' takes a
+  whole function.
+- --verbose-regex ('(?x)'): whitespace in the pattern is ignored, and '#'
+  starts a comment, so a long pattern can be laid out over several lines.
+  '\ ' or '[ ]' matches a space.
+
+--bytes and --stream use a ByteMatcher instead, which works on raw bytes.
+ */
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MatchOptions {
+    pub fixed_strings: bool,
+    pub word: bool,
+    pub ignore_case: bool,
+    pub multi_line: bool,
+    pub dot_all: bool,
+    pub verbose: bool,
+}
+
+#[derive(Debug)]
+pub struct Matcher {
+    engine: Engine,
+}
+
+#[derive(Debug)]
+enum Engine {
+    Regex(Regex),
+    Literal { needle: String, word: bool },
+}
+
+// The start and end of each of a run of matches, in order.
+pub type Spans = Vec<(usize, usize)>;
+
+// Like Spans, for matches that were replaced, with how long each one's replacement came out.
+pub type Replacements = Vec<(usize, usize, usize)>;
+
+// One match, with the replacement text it should be swapped for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String,
+}
+
+impl Matcher {
+    pub fn new(target: &str, options: &MatchOptions) -> Result<Matcher, regex::Error> {
+        let engine = if options.fixed_strings && !options.ignore_case {
+            Engine::Literal { needle: target.to_string(), word: options.word }
+        } else {
+            Engine::Regex(RegexBuilder::new(&pattern(target, options))
+                .case_insensitive(options.ignore_case)
+                .multi_line(options.multi_line)
+                .dot_matches_new_line(options.dot_all)
+                .ignore_whitespace(options.verbose && !options.fixed_strings)
+                .build()?)
+        };
+        Ok(Matcher { engine })
+    }
+
+    // The byte ranges of all non-overlapping matches in 'text'.
+    pub fn find_iter(&self, text: &str) -> Spans {
+        match &self.engine {
+            Engine::Regex(regex) => regex.find_iter(text).map(|m| (m.start(), m.end())).collect(),
+            Engine::Literal { needle, word } => find_literal(text, needle, *word),
+        }
+    }
+
+    // Every match in 'text' along with its expanded replacement.
+    pub fn matches(&self, text: &str, replacement: &Template) -> Result<Vec<Match>, String> {
+        self.matches_where(text, replacement, |_, _| true)
+    }
+
+    /*
+    Like 'matches', but only those for which 'keep' is true given their start
+    and end. Only those count for '$#' in the replacement.
+     */
+    pub fn matches_where(&self, text: &str, replacement: &Template,
+                         keep: impl Fn(usize, usize) -> bool) -> Result<Vec<Match>, String> {
+        let mut found = Vec::new();
+        match &self.engine {
+            Engine::Regex(regex) => for captures in regex.captures_iter(text) {
+                let whole = captures.get(0).unwrap();
+                if keep(whole.start(), whole.end()) {
+                    let group = |group: &Group| match group {
+                        Group::Index(index) => captures.get(*index).map(|m| m.as_str().as_bytes()),
+                        Group::Name(name) => captures.name(name).map(|m| m.as_str().as_bytes()),
+                    };
+                    let replacement = expand(replacement, group, found.len() + 1)?;
+                    found.push(Match { start: whole.start(), end: whole.end(), replacement });
+                }
+            },
+            Engine::Literal { .. } => for (start, end) in self.find_iter(text) {
+                if keep(start, end) {
+                    let replacement = expand(replacement, |_| None, found.len() + 1)?;
+                    found.push(Match { start, end, replacement });
+                }
+            },
+        }
+        Ok(found)
+    }
+
+    // 'text' with every match replaced, and the byte range each match had in 'text'.
+    pub fn replace_all(&self, text: &str, replacement: &Template) -> Result<(String, Replacements), String> {
+        let matches = self.matches(text, replacement)?;
+        Ok((splice(text, &matches), replacements(&matches)))
+    }
+
+    // Like 'replace_all', but only for matches that lie within 'regions'.
+    pub fn replace_within(&self, text: &str, replacement: &Template,
+                          regions: &Regions) -> Result<(String, Replacements), String> {
+        let matches = self.matches_where(text, replacement, |start, end| regions.contains(start, end))?;
+        Ok((splice(text, &matches), replacements(&matches)))
+    }
+}
+
+pub fn replacements(matches: &[Match]) -> Replacements {
+    matches.iter().map(|m| (m.start, m.end, m.replacement.len())).collect()
+}
+
+fn expand<'t>(replacement: &Template, group: impl Fn(&Group) -> Option<&'t [u8]>,
+              number: usize) -> Result<String, String> {
+    let mut expanded = Vec::new();
+    replacement.expand(group, number, &mut expanded)?;
+    Ok(String::from_utf8_lossy(&expanded).into_owned())
+}
+
+/*
+The regex to search for: 'target' itself, or escaped with --fixed-strings,
+and with --word-regexp wrapped in half boundaries, which only look for a
+word character on the outside. A verbose pattern may end in a comment,
+which mustn't swallow the end of the wrapping.
+ */
+fn pattern(target: &str, options: &MatchOptions) -> String {
+    let pattern = if options.fixed_strings { regex::escape(target) } else { target.to_string() };
+    match (options.word, options.verbose && !options.fixed_strings) {
+        (true, true) => format!("\\b{{start-half}}(?:{}\n)\\b{{end-half}}", pattern),
+        (true, false) => format!(r"\b{{start-half}}(?:{})\b{{end-half}}", pattern),
+        (false, _) => pattern,
+    }
+}
+
+/*
+What's wrong with the regex 'target', for an error message: the problem, then
+the line of the pattern it's on with a caret under it,
+
+    unclosed group
+        a(b
+         ^
+
+The pattern shown is the one given, not what --word-regexp made of it. If
+the pattern parses after all, it was too big to compile, and 'error' says so.
+ */
+pub fn describe_error(target: &str, options: &MatchOptions, error: &regex::Error) -> String {
+    let parsed = regex_syntax::ParserBuilder::new()
+        .case_insensitive(options.ignore_case)
+        .multi_line(options.multi_line)
+        .dot_matches_new_line(options.dot_all)
+        .ignore_whitespace(options.verbose)
+        .build()
+        .parse(target);
+    let (message, span) = match parsed {
+        Err(regex_syntax::Error::Parse(e)) => (e.kind().to_string(), *e.span()),
+        Err(regex_syntax::Error::Translate(e)) => (e.kind().to_string(), *e.span()),
+        _ => return error.to_string(),
+    };
+
+    let line = target.lines().nth(span.start.line - 1).unwrap_or("");
+    let width = if span.end.line == span.start.line { span.end.column - span.start.column } else { 1 };
+    let mut out = message;
+    if target.contains('\n') {
+        out += &format!(" (on line {} of the pattern)", span.start.line);
+    }
+    out += &format!("\n    {}\n    {}{}", line, " ".repeat(span.start.column - 1), "^".repeat(width.max(1)));
+    out
+}
+
+// 'text' with each of 'matches', which must be in order, swapped for its replacement.
+pub fn splice(text: &str, matches: &[Match]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for m in matches {
+        out.push_str(&text[last..m.start]);
+        out.push_str(&m.replacement);
+        last = m.end;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+fn find_literal(text: &str, needle: &str, word: bool) -> Spans {
+    let mut found = Vec::new();
+    if needle.is_empty() {
+        return found;
+    }
+    let mut from = 0;
+    while let Some(offset) = text[from..].find(needle) {
+        let (start, end) = (from + offset, from + offset + needle.len());
+        if !word || (!is_word(text[..start].chars().next_back()) && !is_word(text[end..].chars().next())) {
+            found.push((start, end));
+            from = end;
+        } else {
+            // Try again one character further on; the rejected match may overlap a good one.
+            from = start + text[start..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    found
+}
+
+// Whether 'c' is there and a word character, as '\w' has it.
+fn is_word(c: Option<char>) -> bool {
+    c.is_some_and(|c| c.is_alphanumeric() || c == '_')
+}
+
+/*
+A Matcher for text that needn't be UTF-8, built on 'regex::bytes'. Literal
+targets go through an escaped regex too, which keeps the --word-regexp and
+--ignore-case rules the same as for text. Patterns are Unicode-aware as usual;
+'(?-u)' lets '.' or '\xFF' match any single byte.
+ */
+#[derive(Debug)]
+pub struct ByteMatcher {
+    regex: bytes::Regex,
+}
+
+impl ByteMatcher {
+    pub fn new(target: &str, options: &MatchOptions) -> Result<ByteMatcher, regex::Error> {
+        let regex = bytes::RegexBuilder::new(&pattern(target, options))
+            .case_insensitive(options.ignore_case)
+            .multi_line(options.multi_line)
+            .dot_matches_new_line(options.dot_all)
+            .ignore_whitespace(options.verbose && !options.fixed_strings)
+            .build()?;
+        Ok(ByteMatcher { regex })
+    }
+
+    pub fn find_iter(&self, text: &[u8]) -> Spans {
+        self.regex.find_iter(text).map(|m| (m.start(), m.end())).collect()
+    }
+
+    /*
+    'text' with every match replaced, and the byte range each match had in
+    'text'. 'counted' is how many matches came before in the same file, for
+    '$#', when the file is handled a piece at a time.
+     */
+    pub fn replace_all(&self, text: &[u8], replacement: &Template,
+                       counted: usize) -> Result<(Vec<u8>, Replacements), String> {
+        self.replace_where(text, replacement, counted, |_, _| true)
+    }
+
+    pub fn replace_within(&self, text: &[u8], replacement: &Template,
+                          regions: &Regions) -> Result<(Vec<u8>, Replacements), String> {
+        self.replace_where(text, replacement, 0, |start, end| regions.contains(start, end))
+    }
+
+    fn replace_where(&self, text: &[u8], replacement: &Template, counted: usize,
+                     keep: impl Fn(usize, usize) -> bool) -> Result<(Vec<u8>, Replacements), String> {
+        let mut out = Vec::with_capacity(text.len());
+        let mut last = 0;
+        let mut replaced = Vec::new();
+        for captures in self.regex.captures_iter(text) {
+            let whole = captures.get(0).unwrap();
+            if !keep(whole.start(), whole.end()) {
+                continue;
+            }
+            out.extend_from_slice(&text[last..whole.start()]);
+            let group = |group: &Group| match group {
+                Group::Index(index) => captures.get(*index).map(|m| m.as_bytes()),
+                Group::Name(name) => captures.name(name).map(|m| m.as_bytes()),
+            };
+            let before = out.len();
+            replacement.expand(group, counted + replaced.len() + 1, &mut out)?;
+            last = whole.end();
+            replaced.push((whole.start(), whole.end(), out.len() - before));
+        }
+        out.extend_from_slice(&text[last..]);
+        Ok((out, replaced))
+    }
+}
+
+#[test]
+fn test_regex_matcher() {
+    let template = |s| Template::parse(s, false).unwrap();
+    let matcher = Matcher::new(r"(\w+)@(\w+)", &MatchOptions::default()).unwrap();
+    assert_eq!(matcher.replace_all("me@home you@work", &template("$2@$1")),
+               Ok(("home@me work@you".to_string(), vec![(0, 7, 7), (8, 16, 8)])));
+    assert_eq!(matcher.replace_all("a@b c@d", &template(r"\U$1\E$#")).unwrap().0, "A1 C2");
+
+    let word = Matcher::new("cat", &MatchOptions { word: true, ..Default::default() }).unwrap();
+    assert_eq!(word.find_iter("cat concat cat_ cat."), vec![(0, 3), (16, 19)]);
+    let numbered = word.matches_where("cat cat cat", &template("$#"), |start, _| start > 0).unwrap();
+    assert_eq!(numbered.iter().map(|m| m.replacement.as_str()).collect::<Vec<_>>(), vec!["1", "2"]);
+
+    let case = Matcher::new("cat", &MatchOptions { ignore_case: true, ..Default::default() }).unwrap();
+    assert_eq!(case.replace_all("Cat CAT cat", &template("dog")).unwrap().0, "dog dog dog");
+
+    let number = Matcher::new(r"\d+", &MatchOptions::default()).unwrap();
+    assert!(number.replace_all("v1", &template("$($0 + $1)")).is_err());
+
+    assert!(Matcher::new("foo(", &MatchOptions::default()).is_err());
+}
+
+#[test]
+fn test_regex_flags() {
+    let template = |s| Template::parse(s, false).unwrap();
+    let text = "fn a() {\n    x\n}\nfn b() {}\n";
+    let block = MatchOptions { multi_line: true, dot_all: true, ..Default::default() };
+    let matcher = Matcher::new(r"^fn a\(\).*?^}\n", &block).unwrap();
+    assert_eq!(matcher.replace_all(text, &template("")).unwrap().0, "fn b() {}\n");
+    assert_eq!(Matcher::new(r"^fn a\(\).*?^}\n", &MatchOptions::default()).unwrap().find_iter(text), vec![]);
+
+    let verbose = MatchOptions { verbose: true, word: true, ..Default::default() };
+    let matcher = Matcher::new("(\\d+)  # the number\n  \\ apples  # and what of", &verbose).unwrap();
+    assert_eq!(matcher.find_iter("3 apples, 4 applesauce"), vec![(0, 8)]);
+    let fixed = MatchOptions { verbose: true, fixed_strings: true, ..Default::default() };
+    assert_eq!(ByteMatcher::new("a b", &fixed).unwrap().find_iter(b"ab a b"), vec![(3, 6)]);
+}
+
+#[test]
+fn test_describe_error() {
+    let options = MatchOptions { word: true, ..Default::default() };
+    let error = Matcher::new("a(b", &options).unwrap_err();
+    assert_eq!(describe_error("a(b", &options, &error), "unclosed group\n    a(b\n     ^");
+
+    let options = MatchOptions { verbose: true, ..Default::default() };
+    let error = Matcher::new("a  # fine\n  b{2,1}", &options).unwrap_err();
+    let message = describe_error("a  # fine\n  b{2,1}", &options, &error);
+    assert!(message.starts_with("invalid repetition count range"));
+    assert!(message.ends_with(" (on line 2 of the pattern)\n      b{2,1}\n       ^^^^^"));
+}
+
+#[test]
+fn test_literal_matcher() {
+    let template = |s| Template::parse(s, true).unwrap();
+    let fixed = MatchOptions { fixed_strings: true, ..Default::default() };
+    let matcher = Matcher::new("a.b(", &fixed).unwrap();
+    assert_eq!(matcher.replace_all("a.b( axb( a.b(", &template("$1")),
+               Ok(("$1 axb( $1".to_string(), vec![(0, 4, 2), (10, 14, 2)])));
+
+    let word = Matcher::new("cat", &MatchOptions { word: true, ..fixed.clone() }).unwrap();
+    assert_eq!(word.find_iter("concat cat_ cat. (cat)"), vec![(12, 15), (18, 21)]);
+    assert_eq!(word.find_iter("catcat cat"), vec![(7, 10)]);
+    // Whole words by what's either side, even where the target starts or ends with a non-word character.
+    let call = Matcher::new("foo(", &MatchOptions { word: true, ..fixed.clone() }).unwrap();
+    assert_eq!(call.find_iter("foo() foo( x xfoo("), vec![(0, 4), (6, 10)]);
+    let call = Matcher::new("foo(", &MatchOptions { word: true, ignore_case: true, ..fixed.clone() }).unwrap();
+    assert_eq!(call.find_iter("foo() Foo( x xfoo("), vec![(0, 4), (6, 10)]);
+    let dotted = Matcher::new(r"\.x", &MatchOptions { word: true, ..Default::default() }).unwrap();
+    assert_eq!(dotted.find_iter("a.x .x .xy"), vec![(4, 6)]);
+
+    let case = Matcher::new("a.B", &MatchOptions { ignore_case: true, ..fixed.clone() }).unwrap();
+    assert_eq!(case.replace_all("A.b a.B aXb", &template("$0")).unwrap().0, "$0 $0 aXb");
+
+    assert_eq!(Matcher::new("", &fixed).unwrap().find_iter("abc"), vec![]);
+}
+
+#[test]
+fn test_byte_matcher() {
+    let matcher = ByteMatcher::new(r"(\w+)@(\w+)", &MatchOptions::default()).unwrap();
+    let swap = Template::parse("$2@$1", false).unwrap();
+    assert_eq!(matcher.replace_all(b"\xffme@home\xfe", &swap, 0), Ok((b"\xffhome@me\xfe".to_vec(), vec![(1, 8, 7)])));
+
+    let raw = ByteMatcher::new(r"(?-u)\xff+", &MatchOptions::default()).unwrap();
+    let counter = Template::parse("<$#>", false).unwrap();
+    assert_eq!(raw.replace_all(b"a\xff\xffb\xff", &counter, 4), Ok((b"a<5>b<6>".to_vec(), vec![(1, 3, 3), (4, 5, 3)])));
+
+    let fixed = MatchOptions { fixed_strings: true, word: true, ignore_case: true, ..Default::default() };
+    let literal = ByteMatcher::new("a.b", &fixed).unwrap();
+    let dollar = Template::parse("$1", true).unwrap();
+    assert_eq!(literal.replace_all(b"A.B a.bc axb a.b", &dollar, 0).unwrap().0, b"$1 a.bc axb $1");
+}
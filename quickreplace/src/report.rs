@@ -0,0 +1,178 @@
+use serde::Serialize;
+
+/*
+Exit status and --json report
+
+The exit status says how the run went, so scripts needn't scrape messages:
+
+    0  something was replaced, or with --dry-run would have been; for
+       'search', something matched; for 'undo', runs were undone
+    1  nothing was replaced, nothing matched or there was nothing to undo
+    2  the arguments were wrong
+    3  the target, replacement, rules file, a --lines range or a --from,
+       --to or --line-matching regex is invalid
+    4  no file could be read or written, or 'undo' failed
+    5  some files were done but others failed
+
+Errors that stop everything, 2 to 4, are reported before any file is
+touched. A file that fails is reported and skipped, giving 4 or 5 at the
+end.
+
+--json prints a report on stdout once the run is over, instead of diffs and
+counts: the status, totals, the errors, and each file that had a match or
+failed, with the byte range of every match in the file as it was before the
+run. That's in the file's own encoding, whatever it was decoded from to
+match, and with several rules a later rule's matches are traced back
+through the earlier ones' replacements; see 'trail'.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Done = 0,
+    NoMatches = 1,
+    Usage = 2,
+    BadPattern = 3,
+    Failed = 4,
+    PartlyFailed = 5,
+}
+
+impl Status {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub exit_code: i32,
+    pub dry_run: bool,
+    pub matches: usize,
+    pub files_changed: usize,
+    pub files: Vec<FileReport>,
+    // Everything that went wrong, including the errors in 'files'.
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub changed: bool,
+    pub matches: usize,
+    pub offsets: Vec<Offset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// The matches of each rule in each member of an archive that had any.
+pub type Members = Vec<(String, Vec<Vec<(u64, u64)>>)>;
+
+/*
+Where one match was, in bytes, and which rule it was for, counting from 1.
+In an archive, the offsets are in the member named, uncompressed.
+ */
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Offset {
+    pub rule: usize,
+    pub start: u64,
+    pub end: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub member: Option<String>,
+}
+
+impl Report {
+    pub fn new(dry_run: bool) -> Report {
+        Report {
+            status: Status::NoMatches,
+            exit_code: Status::NoMatches.code(),
+            dry_run,
+            matches: 0,
+            files_changed: 0,
+            files: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /*
+    Settle the status from what's been added: every file done, some or none,
+    and whether anything matched. 'done' is how many files were dealt with
+    without error.
+     */
+    pub fn finish(&mut self, done: usize) -> Status {
+        self.status = match (self.errors.is_empty(), done) {
+            (true, _) if self.matches > 0 => Status::Done,
+            (true, _) => Status::NoMatches,
+            (false, 0) => Status::Failed,
+            (false, _) => Status::PartlyFailed,
+        };
+        self.exit_code = self.status.code();
+        self.status
+    }
+
+    // Give up with an error that stops everything.
+    pub fn fail(&mut self, status: Status, message: String) -> Status {
+        self.errors.push(message);
+        self.status = status;
+        self.exit_code = status.code();
+        status
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a report is always valid JSON")
+    }
+}
+
+impl FileReport {
+    // A file that was dealt with; 'matches' has the start and end of each match, for each rule in turn.
+    pub fn new(path: String, changed: bool, matches: &[Vec<(u64, u64)>]) -> FileReport {
+        let offsets: Vec<Offset> = offsets(None, matches).collect();
+        FileReport { path, changed, matches: offsets.len(), offsets, error: None }
+    }
+
+    // An archive, with the matches in each of its 'members'.
+    pub fn archive(path: String, changed: bool, members: &Members) -> FileReport {
+        let offsets: Vec<Offset> = members.iter().flat_map(|(member, matches)| offsets(Some(member), matches))
+            .collect();
+        FileReport { path, changed, matches: offsets.len(), offsets, error: None }
+    }
+
+    // A file whose matches were only counted, as --stream does when there's no --json report to give them.
+    pub fn counted(path: String, changed: bool, matches: usize) -> FileReport {
+        FileReport { path, changed, matches, offsets: Vec::new(), error: None }
+    }
+
+    pub fn failed(path: String, error: String) -> FileReport {
+        FileReport { path, changed: false, matches: 0, offsets: Vec::new(), error: Some(error) }
+    }
+}
+
+fn offsets<'m>(member: Option<&'m String>, matches: &'m [Vec<(u64, u64)>]) -> impl Iterator<Item = Offset> + 'm {
+    matches.iter().enumerate().flat_map(move |(i, spans)| {
+        spans.iter().map(move |&(start, end)| Offset { rule: i + 1, start, end, member: member.cloned() })
+    })
+}
+
+#[test]
+fn test_report() {
+    let mut report = Report::new(true);
+    assert_eq!(report.finish(3), Status::NoMatches);
+
+    let file = FileReport::new("a.txt".to_string(), true, &[vec![(0, 3)], vec![(5, 6), (9, 10)]]);
+    assert_eq!(file.matches, 3);
+    assert_eq!(file.offsets[1], Offset { rule: 2, start: 5, end: 6, member: None });
+    let archive = FileReport::archive("a.zip".to_string(), true, &vec![("x".to_string(), vec![vec![], vec![(1, 2)]])]);
+    assert_eq!(archive.offsets, vec![Offset { rule: 2, start: 1, end: 2, member: Some("x".to_string()) }]);
+    report.matches += file.matches;
+    report.files.push(file);
+    assert_eq!(report.finish(1), Status::Done);
+
+    report.errors.push("failed to read from file 'b.txt'".to_string());
+    assert_eq!(report.finish(1), Status::PartlyFailed);
+    assert_eq!(report.finish(0), Status::Failed);
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["status"], "failed");
+    assert_eq!(json["exit_code"], 4);
+    assert_eq!(json["files"][0]["offsets"][2]["start"], 9);
+    assert!(json["files"][0].get("error").is_none());
+}
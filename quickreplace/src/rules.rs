@@ -0,0 +1,151 @@
+use crate::matcher::MatchOptions;
+
+/*
+Rules files
+
+--rules FILE applies many replacements in one run, each file being read and
+written once however many rules there are. The file holds one rule per line,
+written as in sed:
+
+    # British to American
+    s/colour/color/g
+    s|src/old|src/new|
+    s/\bfoo_(\w+)/bar_$1/i
+
+The character after 's' is the delimiter and can be anything but a letter,
+digit, backslash or space; a delimiter inside the target or replacement is
+escaped with a backslash. Other backslashes are left alone for the regex.
+Blank lines and lines starting with '#' are skipped.
+
+Rules are applied in order, each to the output of the one before, so a later
+rule sees what an earlier one replaced. The flags after the last delimiter
+apply to that rule on top of any given on the command line:
+
+    i  match regardless of case, like --ignore-case
+    w  only replace whole words, like --word-regexp
+    F  plain text rather than a regex, like --fixed-strings
+    m  '^' and '$' match at every line, like --multiline; 'M' too, as in sed
+    s  '.' matches newlines too, like --dot-all
+    x  whitespace and '#' comments in the target are ignored, like --verbose-regex
+    g  accepted for sed's sake; every match is always replaced
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub target: String,
+    pub replacement: String,
+    pub options: MatchOptions,
+    // Where the rule came from in the rules file, for messages; 0 for the command line.
+    pub line: usize,
+}
+
+// The rules in 'text', read from the rules file 'path'.
+pub fn parse_rules_file(path: &str, text: &str, defaults: &MatchOptions) -> Result<Vec<Rule>, String> {
+    let rules = parse_rules(text, defaults).map_err(|e| format!("{}: {}", path, e))?;
+    if rules.is_empty() {
+        return Err(format!("rules file '{}' has no rules", path));
+    }
+    Ok(rules)
+}
+
+pub fn parse_rules(text: &str, defaults: &MatchOptions) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut rule = parse_rule(line, defaults).map_err(|e| format!("line {}: {}", index + 1, e))?;
+        rule.line = index + 1;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+fn parse_rule(line: &str, defaults: &MatchOptions) -> Result<Rule, String> {
+    let expected = || format!("expected s/TARGET/REPLACEMENT/FLAGS, got '{}'", line);
+    let rest = line.strip_prefix('s').ok_or_else(expected)?;
+    let delimiter = rest.chars().next().ok_or_else(expected)?;
+    if delimiter.is_alphanumeric() || delimiter == '\\' || delimiter.is_whitespace() {
+        return Err(format!("'{}' can't be used as a delimiter", delimiter));
+    }
+
+    let mut fields = vec![String::new()];
+    let mut chars = rest[delimiter.len_utf8()..].chars();
+    while let Some(c) = chars.next() {
+        if fields.len() == 3 {
+            // Everything after the last delimiter is flags.
+            fields[2].push(c);
+        } else if c == delimiter {
+            fields.push(String::new());
+        } else if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delimiter => fields.last_mut().unwrap().push(next),
+                Some(next) => {
+                    fields.last_mut().unwrap().push('\\');
+                    fields.last_mut().unwrap().push(next);
+                }
+                None => fields.last_mut().unwrap().push('\\'),
+            }
+        } else {
+            fields.last_mut().unwrap().push(c);
+        }
+    }
+    if fields.len() != 3 {
+        return Err(expected());
+    }
+
+    let mut options = defaults.clone();
+    for flag in fields[2].trim().chars() {
+        match flag {
+            'i' | 'I' => options.ignore_case = true,
+            'w' => options.word = true,
+            'F' => options.fixed_strings = true,
+            'm' | 'M' => options.multi_line = true,
+            's' => options.dot_all = true,
+            'x' => options.verbose = true,
+            'g' => {}
+            _ => return Err(format!("unknown flag '{}'", flag)),
+        }
+    }
+    Ok(Rule { target: fields[0].clone(), replacement: fields[1].clone(), options, line: 0 })
+}
+
+/*
+How often each rule fired over the whole run, and in how many files, for
+printing once everything is done.
+ */
+pub fn summary(rules: &[Rule], matches: &[usize], files: &[usize]) -> String {
+    let mut out = String::new();
+    for ((rule, &matches), &files) in rules.iter().zip(matches).zip(files) {
+        out += &format!("{} {} in {} {}: s/{}/{}/ (line {})\n", matches,
+                        if matches == 1 { "match" } else { "matches" }, files,
+                        if files == 1 { "file" } else { "files" }, rule.target, rule.replacement, rule.line);
+    }
+    out
+}
+
+#[test]
+fn test_parse_rules() {
+    let defaults = MatchOptions { word: true, ..Default::default() };
+    let rules = parse_rules("# renames\n\ns/colour/color/g\n  s|a/b|c\\|d|iF  \ns/x\\/y/\\1/\n", &defaults).unwrap();
+    assert_eq!(rules, vec![
+        Rule { target: "colour".to_string(), replacement: "color".to_string(), options: defaults.clone(), line: 3 },
+        Rule {
+            target: "a/b".to_string(),
+            replacement: "c|d".to_string(),
+            options: MatchOptions { fixed_strings: true, word: true, ignore_case: true, ..Default::default() },
+            line: 4,
+        },
+        Rule { target: "x/y".to_string(), replacement: "\\1".to_string(), options: defaults.clone(), line: 5 },
+    ]);
+
+    assert!(parse_rules("s/a/b/\nt/a/b/\n", &defaults).unwrap_err().starts_with("line 2:"));
+    let flags = parse_rules("s/^a.b/c/msx\n", &defaults).unwrap();
+    assert!(flags[0].options.multi_line && flags[0].options.dot_all && flags[0].options.verbose);
+    assert!(parse_rules("s/a/b", &defaults).is_err());
+    assert!(parse_rules("s/a/b/q", &defaults).is_err());
+    assert!(parse_rules("sxaxbx", &defaults).is_err());
+
+    let summary = summary(&rules[..1], &[3], &[1]);
+    assert_eq!(summary, "3 matches in 1 file: s/colour/color/ (line 3)\n");
+}
@@ -0,0 +1,257 @@
+use std::path::Path;
+
+use regex::bytes::Regex;
+
+use crate::matcher::describe_error;
+use crate::syntax::{self, Language, Part};
+
+/*
+Scoped replacement
+
+By default every match in a file is replaced. These options narrow that down
+to some of its lines:
+
+    --lines RANGE           lines N, N:M, N: or :M, counted from 1; RANGE
+                            can be a comma-separated list, and the option
+                            can be repeated
+    --line-matching REGEX   lines REGEX matches
+    --from REGEX            from a line REGEX matches up to and including
+    --to REGEX              the next line the --to REGEX matches, or to the
+                            end of the file without --to; the region can
+                            start again after it ends, as in sed
+
+A line must pass every option given. A match is only replaced if it lies
+entirely within lines in scope. The regexes are matched against each line
+without its line ending, so a config section is
+
+    --from '^\[database\]' --to '^\['
+
+--only code, comments or strings narrows things down within lines as well;
+see 'syntax'.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct ScopeOptions {
+    pub lines: Vec<String>,
+    pub line_matching: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub only: Option<Part>,
+    // The language for --only, if not to be told from each file's name.
+    pub language: Option<Language>,
+}
+
+impl ScopeOptions {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.line_matching.is_none() && self.from.is_none() && self.to.is_none() &&
+            self.only.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct Scope {
+    // Inclusive line number ranges; None for "to the end".
+    ranges: Vec<(usize, Option<usize>)>,
+    line_matching: Option<Regex>,
+    from: Option<Regex>,
+    to: Option<Regex>,
+    only: Option<Part>,
+    language: Option<Language>,
+}
+
+impl Scope {
+    // The scope the options describe, or None if they don't narrow anything down.
+    pub fn new(options: &ScopeOptions) -> Result<Option<Scope>, String> {
+        if options.is_empty() {
+            return Ok(None);
+        }
+        let mut ranges = Vec::new();
+        for list in &options.lines {
+            for range in list.split(',') {
+                ranges.push(parse_range(range.trim())?);
+            }
+        }
+        let regex = |option: &str, pattern: &Option<String>| match pattern {
+            Some(pattern) => Regex::new(pattern).map(Some).map_err(|e| {
+                format!("invalid {} regex: {}", option, describe_error(pattern, &Default::default(), &e))
+            }),
+            None => Ok(None),
+        };
+        Ok(Some(Scope {
+            ranges,
+            line_matching: regex("--line-matching", &options.line_matching)?,
+            from: regex("--from", &options.from)?,
+            to: regex("--to", &options.to)?,
+            only: options.only,
+            language: options.language,
+        }))
+    }
+
+    // Something to ask about each line of a file in turn.
+    pub fn tracker(&self) -> Tracker<'_> {
+        Tracker { scope: self, number: 0, inside: false }
+    }
+
+    /*
+    The parts of 'text', the contents of the file 'path', in scope: whole
+    lines, line endings included, or with --only the code, comments or
+    strings on them. Fails if --only needs a language that can't be told.
+     */
+    pub fn regions(&self, text: &[u8], path: &Path) -> Result<Regions, String> {
+        let lines = self.line_regions(text);
+        let part = match self.only {
+            Some(part) => part,
+            None => return Ok(lines),
+        };
+        let language = self.language.or_else(|| Language::for_path(path)).ok_or_else(|| {
+            format!("can't tell what language '{}' is in from its name; use --language", path.display())
+        })?;
+        Ok(lines.intersect(&Regions(syntax::regions(text, language, part))))
+    }
+
+    fn line_regions(&self, text: &[u8]) -> Regions {
+        let mut tracker = self.tracker();
+        let mut regions: Vec<(usize, usize)> = Vec::new();
+        let mut start = 0;
+        while start < text.len() {
+            let end = text[start..].iter().position(|&b| b == b'\n').map_or(text.len(), |i| start + i + 1);
+            let line = &text[start..end];
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if tracker.in_scope(line) {
+                match regions.last_mut() {
+                    Some(last) if last.1 == start => last.1 = end,
+                    _ => regions.push((start, end)),
+                }
+            }
+            start = end;
+        }
+        Regions(regions)
+    }
+}
+
+// Parse 'N', 'N:M', 'N:' or ':M'.
+fn parse_range(range: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || format!("invalid line range '{}'; expected N, N:M, N: or :M", range);
+    let number = |s: &str| s.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(invalid);
+    let (first, last) = match range.split_once(':') {
+        None => (number(range)?, Some(number(range)?)),
+        Some(("", "")) => return Err(invalid()),
+        Some((first, last)) => (
+            if first.is_empty() { 1 } else { number(first)? },
+            if last.is_empty() { None } else { Some(number(last)?) },
+        ),
+    };
+    if last.is_some_and(|last| last < first) {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+// Follows a file line by line, as --from and --to need to.
+pub struct Tracker<'a> {
+    scope: &'a Scope,
+    number: usize,
+    inside: bool,
+}
+
+impl Tracker<'_> {
+    // Whether the next line, given without its line ending, is in scope.
+    pub fn in_scope(&mut self, line: &[u8]) -> bool {
+        self.number += 1;
+        let scope = self.scope;
+
+        let in_region = match &scope.from {
+            None => true,
+            Some(_) if self.inside => {
+                if scope.to.as_ref().is_some_and(|to| to.is_match(line)) {
+                    self.inside = false;
+                }
+                true
+            }
+            Some(from) => {
+                self.inside = from.is_match(line);
+                self.inside
+            }
+        };
+        let in_ranges = scope.ranges.is_empty() || scope.ranges.iter()
+            .any(|&(first, last)| first <= self.number && last.is_none_or(|last| self.number <= last));
+        let matching = scope.line_matching.as_ref().is_none_or(|regex| regex.is_match(line));
+
+        in_region && in_ranges && matching
+    }
+}
+
+// Byte ranges of a text, in order and not overlapping; a comment or string is one of its own.
+#[derive(Debug, PartialEq)]
+pub struct Regions(Vec<(usize, usize)>);
+
+impl Regions {
+    // Whether the text from 'start' to 'end' lies entirely within one region.
+    pub fn contains(&self, start: usize, end: usize) -> bool {
+        let after = self.0.partition_point(|&(first, _)| first <= start);
+        after > 0 && end <= self.0[after - 1].1
+    }
+
+    // The parts of the text in both these regions and 'other'.
+    fn intersect(&self, other: &Regions) -> Regions {
+        let mut both = Vec::new();
+        let (mut a, mut b) = (self.0.iter().peekable(), other.0.iter().peekable());
+        while let (Some(&&(a_start, a_end)), Some(&&(b_start, b_end))) = (a.peek(), b.peek()) {
+            let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+            if start < end {
+                both.push((start, end));
+            }
+            if a_end <= b_end { a.next(); } else { b.next(); }
+        }
+        Regions(both)
+    }
+}
+
+#[test]
+fn test_scope() {
+    assert!(Scope::new(&ScopeOptions::default()).unwrap().is_none());
+
+    let text = b"a=1\n[db]\na=2\n\n[web]\na=3\n[db]\r\na=4\n";
+    let section = ScopeOptions {
+        from: Some(r"^\[db\]$".to_string()),
+        to: Some(r"^\[".to_string()),
+        ..Default::default()
+    };
+    let scope = Scope::new(&section).unwrap().unwrap();
+    let regions = scope.regions(text, Path::new("a.ini")).unwrap();
+    assert_eq!(regions, Regions(vec![(4, 20), (24, 34)]));
+    assert!(regions.contains(9, 12));
+    assert!(!regions.contains(0, 3));
+    assert!(!regions.contains(19, 25));
+
+    let lines = ScopeOptions {
+        lines: vec!["2:3,7:".to_string()],
+        line_matching: Some("a".to_string()),
+        ..Default::default()
+    };
+    let scope = Scope::new(&lines).unwrap().unwrap();
+    let mut tracker = scope.tracker();
+    let in_scope: Vec<bool> = text.split(|&b| b == b'\n').take(8).map(|line| tracker.in_scope(line)).collect();
+    assert_eq!(in_scope, vec![false, false, true, false, false, false, false, true]);
+
+    // Only the comments, and only on line 2.
+    let comments = ScopeOptions { lines: vec!["2".to_string()], only: Some(Part::Comments), ..Default::default() };
+    let scope = Scope::new(&comments).unwrap().unwrap();
+    let code = b"// one\nx = 1 // two\n/* three\nfour */\n";
+    assert_eq!(scope.regions(code, Path::new("a.js")).unwrap(), Regions(vec![(13, 19)]));
+    assert!(scope.regions(code, Path::new("a.txt")).is_err());
+    let forced = Scope::new(&ScopeOptions { language: Some(Language::Python), ..comments }).unwrap().unwrap();
+    assert_eq!(forced.regions(b"x\ny # z\n", Path::new("-")).unwrap(), Regions(vec![(4, 7)]));
+    // A match can't run from one string into the next.
+    let strings = Scope::new(&ScopeOptions { only: Some(Part::Strings), ..Default::default() }).unwrap().unwrap();
+    let regions = strings.regions(b"f(\"s\"\"t\")", Path::new("a.c")).unwrap();
+    assert!(regions.contains(2, 5) && !regions.contains(4, 6));
+
+    assert_eq!(parse_range("5"), Ok((5, Some(5))));
+    assert_eq!(parse_range(":3"), Ok((1, Some(3))));
+    assert_eq!(parse_range("4:"), Ok((4, None)));
+    assert!(parse_range("0").is_err());
+    assert!(parse_range("5:2").is_err());
+    assert!(parse_range(":").is_err());
+    assert!(parse_range("x").is_err());
+}
@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use text_colorizer::*;
+
+/*
+Search mode
+
+'quickreplace search <target> [FILE|GLOB|PATH]...' only looks, so a target
+can be tried out before choosing its replacement. Files are found and
+matched just as for a replacement: -r walks directories, -F, -w and
+--ignore-case change what matches, and --lines, --from and the rest limit
+where. Each line holding a match is printed as
+
+    path:line:column:text
+
+with the matches highlighted; the column is that of the line's first match,
+in bytes counting from 1. A match spanning several lines shows them all.
+Instead of lines:
+
+    -c, --count               print 'path:count', the number of matches
+    -l, --files-with-matches  print just the names of files with a match
+    -C, --context N           show N lines before and after each match, as
+                              'path-line-text', with '--' between groups
+ */
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchOptions {
+    pub count: bool,
+    pub files_only: bool,
+    pub context: usize,
+}
+
+// What to print for the matches 'spans' in 'text', read from the file 'name'.
+pub fn show(name: &str, text: &[u8], spans: &[(usize, usize)], options: &SearchOptions) -> String {
+    if spans.is_empty() {
+        return String::new();
+    }
+    // Plain, since these are for feeding to other programs.
+    if options.files_only {
+        return format!("{}\n", name);
+    }
+    if options.count {
+        return format!("{}:{}\n", name, spans.len());
+    }
+
+    let lines = line_ranges(text);
+    let line_of = |offset: usize| lines.partition_point(|&(start, _)| start <= offset) - 1;
+
+    // The parts of each line that matched.
+    let mut highlights: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+    for &(start, end) in spans {
+        let (first_line, last_line) = (line_of(start), line_of(end.saturating_sub(1).max(start)));
+        for (line, &(line_start, line_end)) in lines.iter().enumerate().take(last_line + 1).skip(first_line) {
+            let first = start.clamp(line_start, line_end);
+            highlights.entry(line).or_default().push((first, end.clamp(first, line_end)));
+        }
+    }
+    let shown: BTreeSet<usize> = highlights.keys()
+        .flat_map(|&line| line.saturating_sub(options.context)..=(line + options.context).min(lines.len() - 1))
+        .collect();
+
+    let mut out = String::new();
+    let mut previous = None;
+    for line in shown {
+        if options.context > 0 && previous.is_some_and(|previous| line > previous + 1) {
+            out += "--\n";
+        }
+        let (start, end) = lines[line];
+        match highlights.get(&line) {
+            Some(parts) => out += &format!("{}:{}:{}:{}\n", name.bold(), (line + 1).to_string().green(),
+                                           parts[0].0 - start + 1, highlight(text, (start, end), parts)),
+            None => out += &format!("{}-{}-{}\n", name, line + 1, String::from_utf8_lossy(&text[start..end])),
+        }
+        previous = Some(line);
+    }
+    out
+}
+
+// Where each line of 'text' starts and ends, line endings left out. There's always at least one.
+fn line_ranges(text: &[u8]) -> Vec<(usize, usize)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, &byte) in text.iter().enumerate() {
+        if byte == b'\n' {
+            let end = if i > start && text[i - 1] == b'\r' { i - 1 } else { i };
+            lines.push((start, end));
+            start = i + 1;
+        }
+    }
+    if start < text.len() || lines.is_empty() {
+        lines.push((start, text.len()));
+    }
+    lines
+}
+
+// The line from 'start' to 'end' with 'parts' of it, in order, in red.
+fn highlight(text: &[u8], (start, end): (usize, usize), parts: &[(usize, usize)]) -> String {
+    let mut out = String::new();
+    let mut last = start;
+    for &(first, after) in parts {
+        out += &String::from_utf8_lossy(&text[last..first]);
+        out += &String::from_utf8_lossy(&text[first..after]).red().bold().to_string();
+        last = after;
+    }
+    out += &String::from_utf8_lossy(&text[last..end]);
+    out
+}
+
+#[test]
+fn test_show() {
+    let text = b"one\r\ntwo foo foo\nthree\nfour\nfive foo\nsix\n";
+    let first = 9;
+    let spans = vec![(first, first + 3), (first + 4, first + 7), (33, 36)];
+    let plain = |options: &SearchOptions| crate::diff::strip_colors(&show("a.txt", text, &spans, options));
+
+    assert_eq!(plain(&SearchOptions::default()), "a.txt:2:5:two foo foo\na.txt:5:6:five foo\n");
+    assert_eq!(plain(&SearchOptions { context: 1, ..Default::default() }),
+               "a.txt-1-one\na.txt:2:5:two foo foo\na.txt-3-three\na.txt-4-four\na.txt:5:6:five foo\na.txt-6-six\n");
+    assert_eq!(crate::diff::strip_colors(&show("c", b"a\nb\nc\nd\ne", &[(0, 1), (8, 9)],
+                                               &SearchOptions { context: 1, ..Default::default() })),
+               "c:1:1:a\nc-2-b\n--\nc-4-d\nc:5:1:e\n");
+    assert_eq!(plain(&SearchOptions { count: true, ..Default::default() }), "a.txt:3\n");
+    assert_eq!(plain(&SearchOptions { files_only: true, ..Default::default() }), "a.txt\n");
+    assert_eq!(show("a.txt", text, &[], &SearchOptions::default()), "");
+
+    // A match across lines shows each of them.
+    assert_eq!(crate::diff::strip_colors(&show("b", b"ab\ncd", &[(1, 4)], &SearchOptions::default())),
+               "b:1:2:ab\nb:2:1:cd\n");
+    assert_eq!(line_ranges(b""), vec![(0, 0)]);
+}
@@ -0,0 +1,182 @@
+use std::io::{self, BufRead, Write};
+
+use crate::endings;
+use crate::matcher::ByteMatcher;
+use crate::scope::Scope;
+use crate::template::Template;
+use crate::trail::Trail;
+
+/*
+Streaming replacement
+
+--stream reads and writes one line at a time instead of loading the whole
+file, so a multi-gigabyte log takes no more memory than its longest line.
+The price is that a match can't span lines: each line is matched without its
+line ending ('\n' or '\r\n'), which is written back as it was, so '^' and '$'
+match at the start and end of every line. A line break put into a line
+ending '\r\n' is written '\r\n' too, and a byte order mark is kept out of
+the first line's matching; see 'endings'.
+
+Each of the 'rules', a matcher and its replacement, is applied to the line in
+turn; lines out of 'scope' are copied as they are. Without --bytes each line
+must be UTF-8, as whole files must be otherwise. 'changed' is called for
+every line that had a match, for --dry-run and the undo journal; an error
+from it stops the replacing.
+
+'found' counts each rule's matches and may keep the byte range of every
+one in the input, traced back through the rules before it just as when the
+whole file is read; see 'trail'. It's filled in as the lines go by, so the
+matches in the lines done before an error, such as the output's reader
+going away, still count.
+ */
+pub fn replace_lines(mut input: impl BufRead, mut output: impl Write, rules: &[(ByteMatcher, Template)],
+                     scope: Option<&Scope>, utf8: bool, found: &mut Found,
+                     mut changed: impl FnMut(&Change) -> io::Result<()>) -> io::Result<()> {
+    let mut tracker = scope.map(Scope::tracker);
+    let mut line = Vec::new();
+    found.counts.resize(rules.len(), 0);
+    if let Some(spans) = &mut found.spans {
+        spans.resize(rules.len(), Vec::new());
+    }
+    // Where the line starts in the input.
+    let mut start = 0;
+    let mut number = 0;
+    let mut written = 0;
+
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+        if number == 1 && line.starts_with(endings::BOM) {
+            output.write_all(endings::BOM)?;
+            line.drain(..endings::BOM.len());
+            written += endings::BOM.len() as u64;
+            start += endings::BOM.len() as u64;
+        }
+
+        let (body, ending) = split_line_ending(&line);
+        if utf8 && std::str::from_utf8(body).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("line {} is not valid UTF-8 (use --bytes to edit it anyway)", number)));
+        }
+        if tracker.as_mut().is_some_and(|tracker| !tracker.in_scope(body)) {
+            output.write_all(&line)?;
+            written += line.len() as u64;
+            start += line.len() as u64;
+            continue;
+        }
+
+        let mut replaced = body.to_vec();
+        let mut matched = false;
+        let mut trail = Trail::default();
+        for (i, (matcher, replacement)) in rules.iter().enumerate() {
+            let (new, replacements) = matcher.replace_all(&replaced, replacement, found.counts[i])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number, e)))?;
+            found.counts[i] += replacements.len();
+            if let Some(spans) = &mut found.spans {
+                spans[i].extend(replacements.iter().map(|&(first, last, _)| {
+                    let (first, last) = trail.back(first, last);
+                    (start + first as u64, start + last as u64)
+                }));
+                trail.push(&replacements);
+            }
+            if !replacements.is_empty() {
+                replaced = new;
+                matched = true;
+            }
+        }
+        start += line.len() as u64;
+        if matched && ending == b"\r\n" {
+            replaced = endings::to_crlf(&replaced);
+        }
+        if matched {
+            changed(&Change { number, offset: written, old: body, new: &replaced })?;
+        }
+        output.write_all(&replaced)?;
+        output.write_all(ending)?;
+        written += (replaced.len() + ending.len()) as u64;
+    }
+    output.flush()
+}
+
+// How many matches each rule has had, and if they're being kept, the start and end of each.
+#[derive(Debug, Default)]
+pub struct Found {
+    pub counts: Vec<usize>,
+    pub spans: Option<Vec<Vec<(u64, u64)>>>,
+}
+
+impl Found {
+    pub fn new(keep_spans: bool) -> Found {
+        Found { counts: Vec::new(), spans: keep_spans.then(Vec::new) }
+    }
+
+    // Whether there's been any match at all.
+    pub fn any(&self) -> bool {
+        self.counts.iter().any(|&count| count > 0)
+    }
+}
+
+// A line that had a match, without its line ending.
+pub struct Change<'a> {
+    pub number: usize,
+    // Where the new text starts in the output.
+    pub offset: u64,
+    pub old: &'a [u8],
+    pub new: &'a [u8],
+}
+
+fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let body = line.strip_suffix(b"\n").unwrap_or(line);
+    let body = body.strip_suffix(b"\r").filter(|_| body.len() < line.len()).unwrap_or(body);
+    line.split_at(body.len())
+}
+
+#[test]
+fn test_replace_lines() {
+    let rule = |target, replacement| (ByteMatcher::new(target, &Default::default()).unwrap(),
+                                      Template::parse(replacement, false).unwrap());
+    let rules = vec![rule("o+$", "0"), rule("f0|b", "<$0>")];
+    let input = b"foo\r\nboo\xff\nzoo";
+    let mut output = Vec::new();
+    let mut changes = Vec::new();
+    let mut found = Found::new(true);
+    replace_lines(&input[..], &mut output, &rules, None, false, &mut found,
+                  |c| {
+                      changes.push((c.number, c.offset, c.old.to_vec(), c.new.to_vec()));
+                      Ok(())
+                  }).unwrap();
+    assert_eq!(found.spans, Some(vec![vec![(1, 3), (11, 13)], vec![(0, 3), (5, 6)]]));
+    assert_eq!(found.counts, vec![2, 2]);
+    assert_eq!(output, b"<f0>\r\n<b>oo\xff\nz0");
+    assert_eq!(changes, vec![(1, 0, b"foo".to_vec(), b"<f0>".to_vec()),
+                             (2, 6, b"boo\xff".to_vec(), b"<b>oo\xff".to_vec()),
+                             (3, 13, b"zoo".to_vec(), b"z0".to_vec())]);
+
+    let mut found = Found::new(true);
+    let error = replace_lines(&input[..], io::sink(), &rules, None, true, &mut found, |_| Ok(())).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // The first line was done before the second turned out not to be UTF-8.
+    assert_eq!(found.spans, Some(vec![vec![(1, 3)], vec![(0, 3)]]));
+
+    let scope = Scope::new(&crate::scope::ScopeOptions { lines: vec!["2:".to_string()], ..Default::default() })
+        .unwrap().unwrap();
+    let mut output = Vec::new();
+    // Without the spans the matches are only counted.
+    let mut found = Found::new(false);
+    replace_lines(&input[..], &mut output, &rules, Some(&scope), false, &mut found, |_| Ok(())).unwrap();
+    assert_eq!((found.counts, found.spans), (vec![1, 1], None));
+    assert_eq!(output, b"foo\r\n<b>oo\xff\nz0");
+
+    assert_eq!(split_line_ending(b"a\r"), (&b"a\r"[..], &b""[..]));
+
+    // The byte order mark isn't part of the first line, and new breaks match the line's ending.
+    let rules = vec![rule("^a", "x\ny")];
+    let mut output = Vec::new();
+    let mut found = Found::new(true);
+    replace_lines(&b"\xEF\xBB\xBFab\r\nab\n"[..], &mut output, &rules, None, true, &mut found, |_| Ok(())).unwrap();
+    assert_eq!(found.spans, Some(vec![vec![(3, 4), (7, 8)]]));
+    assert_eq!(output, b"\xEF\xBB\xBFx\r\nyb\r\nx\nyb\n");
+}
@@ -0,0 +1,310 @@
+use std::path::Path;
+
+/*
+Syntax-aware replacement
+
+Renaming an identifier shouldn't rewrite the comment that mentions it or the
+error message that happens to contain the word. --only code, comments or
+strings restricts replacement to that part of each file, and a match must
+lie entirely within one comment, one string, or one stretch of code between
+them. Comments and strings include their delimiters, so '"foo"' can be
+matched with --only strings.
+
+Files are split into these parts by a tokenizer that knows just enough of
+each language to find its comments and string literals:
+
+    rust    // and nested /* */ comments; "...", r#"..."#, b"..." and char
+            literals, which are strings here, while lifetimes are code
+    c       // and /* */ comments; "...", '...' and `...` literals; used for
+            C, C++, Java, JavaScript, TypeScript, Go, C#, Swift and Kotlin
+    python  # comments; '...', "...", and triple-quoted strings, with any
+            prefix such as r or f left as code
+    json    "..." strings, and // and /* */ comments as in JSONC
+
+The language comes from the file's extension unless --language says.
+Preprocessor directives, regex literals and string interpolation aren't
+understood: '${a}' in a JavaScript template is part of the string.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    Rust,
+    C,
+    Python,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Code,
+    Comments,
+    Strings,
+}
+
+// What a tokenizer needs to know about a language, besides Rust's own kinds of literal.
+struct Grammar {
+    line_comment: &'static str,
+    block_comment: Option<(&'static str, &'static str)>,
+    nested_comments: bool,
+    // Each quote, longest first, and whether its strings can run across lines.
+    quotes: &'static [(&'static str, bool)],
+}
+
+impl Language {
+    pub fn from_name(name: &str) -> Result<Language, String> {
+        match name {
+            "rust" => Ok(Language::Rust),
+            "c" => Ok(Language::C),
+            "python" => Ok(Language::Python),
+            "json" => Ok(Language::Json),
+            _ => Err(format!("unknown language '{}'; expected rust, c, python or json", name)),
+        }
+    }
+
+    // The language of the file 'path', going by its extension.
+    pub fn for_path(path: &Path) -> Option<Language> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "rs" => Some(Language::Rust),
+            "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" | "java" | "js" | "mjs" | "cjs" | "jsx" |
+            "ts" | "tsx" | "go" | "cs" | "swift" | "kt" | "kts" => Some(Language::C),
+            "py" | "pyi" | "pyw" => Some(Language::Python),
+            "json" | "jsonc" => Some(Language::Json),
+            _ => None,
+        }
+    }
+
+    fn grammar(self) -> Grammar {
+        match self {
+            Language::Rust => Grammar {
+                line_comment: "//",
+                block_comment: Some(("/*", "*/")),
+                nested_comments: true,
+                quotes: &[("\"", true)],
+            },
+            Language::C => Grammar {
+                line_comment: "//",
+                block_comment: Some(("/*", "*/")),
+                nested_comments: false,
+                quotes: &[("\"", false), ("'", false), ("`", true)],
+            },
+            Language::Python => Grammar {
+                line_comment: "#",
+                block_comment: None,
+                nested_comments: false,
+                quotes: &[("\"\"\"", true), ("'''", true), ("\"", false), ("'", false)],
+            },
+            Language::Json => Grammar {
+                line_comment: "//",
+                block_comment: Some(("/*", "*/")),
+                nested_comments: false,
+                quotes: &[("\"", false)],
+            },
+        }
+    }
+}
+
+impl Part {
+    pub fn from_name(name: &str) -> Result<Part, String> {
+        match name {
+            "code" => Ok(Part::Code),
+            "comments" => Ok(Part::Comments),
+            "strings" => Ok(Part::Strings),
+            _ => Err(format!("unknown part '{}'; expected code, comments or strings", name)),
+        }
+    }
+}
+
+// The comments and strings in 'text', in order, with where each starts and ends.
+pub fn tokens(text: &[u8], language: Language) -> Vec<(Part, usize, usize)> {
+    let grammar = language.grammar();
+    let mut found = Vec::new();
+    let mut at = 0;
+    while at < text.len() {
+        match token_at(text, at, language, &grammar) {
+            Some((part, end)) => {
+                found.push((part, at, end));
+                at = end;
+            }
+            None => at += 1,
+        }
+    }
+    found
+}
+
+/*
+The parts of 'text' that are 'part', in order. Each comment or string is a
+region of its own, even right next to another, so that a match can't run
+from one into the next: "a""b" isn't the string 'a""b'.
+ */
+pub fn regions(text: &[u8], language: Language, part: Part) -> Vec<(usize, usize)> {
+    let mut regions = Vec::new();
+    let mut add = |start: usize, end: usize| if start < end {
+        regions.push((start, end));
+    };
+    let mut code = 0;
+    for (kind, start, end) in tokens(text, language) {
+        if part == Part::Code {
+            add(code, start);
+        } else if kind == part {
+            add(start, end);
+        }
+        code = end;
+    }
+    if part == Part::Code {
+        add(code, text.len());
+    }
+    regions
+}
+
+// The comment or string starting at 'at', if there is one, and where it ends.
+fn token_at(text: &[u8], at: usize, language: Language, grammar: &Grammar) -> Option<(Part, usize)> {
+    let rest = &text[at..];
+    if rest.starts_with(grammar.line_comment.as_bytes()) {
+        let end = rest.iter().position(|&byte| byte == b'\n').map_or(text.len(), |i| at + i);
+        return Some((Part::Comments, end));
+    }
+    if let Some((open, close)) = grammar.block_comment.filter(|(open, _)| rest.starts_with(open.as_bytes())) {
+        return Some((Part::Comments, comment_end(text, at, open, close, grammar.nested_comments)));
+    }
+    if language == Language::Rust {
+        if let Some(end) = raw_string_end(text, at) {
+            return Some((Part::Strings, end));
+        }
+        if rest[0] == b'\'' {
+            return char_end(text, at).map(|end| (Part::Strings, end));
+        }
+    }
+    let &(quote, multiline) = grammar.quotes.iter().find(|(quote, _)| rest.starts_with(quote.as_bytes()))?;
+    Some((Part::Strings, string_end(text, at + quote.len(), quote, multiline)))
+}
+
+// Where the block comment at 'at' ends; an unclosed one runs to the end.
+fn comment_end(text: &[u8], at: usize, open: &str, close: &str, nested: bool) -> usize {
+    let mut depth = 0;
+    let mut i = at;
+    while i < text.len() {
+        if text[i..].starts_with(open.as_bytes()) && (nested || depth == 0) {
+            depth += 1;
+            i += open.len();
+        } else if text[i..].starts_with(close.as_bytes()) {
+            depth -= 1;
+            i += close.len();
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    text.len()
+}
+
+/*
+Where the string whose text starts at 'from' ends, just after its closing
+'quote'. A backslash escapes whatever follows it. A string left open runs to
+the end of its line, or of the text if it's 'multiline'.
+ */
+fn string_end(text: &[u8], from: usize, quote: &str, multiline: bool) -> usize {
+    let mut i = from;
+    while i < text.len() {
+        if text[i] == b'\\' {
+            i += 2;
+        } else if text[i..].starts_with(quote.as_bytes()) {
+            return i + quote.len();
+        } else if text[i] == b'\n' && !multiline {
+            return i;
+        } else {
+            i += 1;
+        }
+    }
+    text.len()
+}
+
+// Where the Rust raw string at 'at', r"..." or br#"..."# and so on, ends, if that's what's there.
+fn raw_string_end(text: &[u8], at: usize) -> Option<usize> {
+    if at > 0 && (text[at - 1].is_ascii_alphanumeric() || text[at - 1] == b'_') {
+        return None;
+    }
+    let mut i = at + usize::from(matches!(text[at], b'b' | b'c'));
+    if text.get(i) != Some(&b'r') {
+        return None;
+    }
+    i += 1;
+    let hashes = text[i..].iter().take_while(|&&byte| byte == b'#').count();
+    i += hashes;
+    if text.get(i) != Some(&b'"') {
+        return None;
+    }
+    let close = [&b"\""[..], &vec![b'#'; hashes]].concat();
+    Some(text[i + 1..].windows(close.len()).position(|window| window == close)
+        .map_or(text.len(), |end| i + 1 + end + close.len()))
+}
+
+// Where the Rust char literal at 'at' ends, or None for a lifetime such as 'a.
+fn char_end(text: &[u8], at: usize) -> Option<usize> {
+    match text.get(at + 1)? {
+        b'\\' => Some(string_end(text, at + 1, "'", false)),
+        &lead => {
+            // The one character between the quotes may take several bytes.
+            let after = at + 1 + match lead { 0xF0.. => 4, 0xE0.. => 3, 0xC0.. => 2, _ => 1 };
+            (text.get(after) == Some(&b'\'')).then_some(after + 1)
+        }
+    }
+}
+
+#[test]
+fn test_tokens() {
+    let text = |tokens: Vec<(Part, usize, usize)>, source: &str| tokens.iter()
+        .map(|&(part, start, end)| (part, source[start..end].to_string()))
+        .collect::<Vec<_>>();
+    let (comment, string) = (|s: &str| (Part::Comments, s.to_string()), |s: &str| (Part::Strings, s.to_string()));
+
+    let rust = "fn f<'a>(x: &'a str) -> char { // \"not\" a string\n    /* outer /* inner */ still */\n    \
+                let s = r#\"raw \" // no\"#; let c = '\\''; let d = 'é'; b\"x\\\"y\" }";
+    assert_eq!(text(tokens(rust.as_bytes(), Language::Rust), rust), vec![
+        comment("// \"not\" a string"),
+        comment("/* outer /* inner */ still */"),
+        string("r#\"raw \" // no\"#"),
+        string("'\\''"),
+        string("'é'"),
+        string("\"x\\\"y\""),
+    ]);
+
+    let c = "int x = 1; /* a /* b */ char *s = \"//\"; // c 'q'\nchar q = '\"'; `multi\nline`";
+    assert_eq!(text(tokens(c.as_bytes(), Language::C), c), vec![
+        comment("/* a /* b */"),
+        string("\"//\""),
+        comment("// c 'q'"),
+        string("'\"'"),
+        string("`multi\nline`"),
+    ]);
+
+    let python = "x = f'{a}' # it's\ns = \"\"\"doc \"quoted\"\n\"\"\" + 'open\ny = 1";
+    assert_eq!(text(tokens(python.as_bytes(), Language::Python), python), vec![
+        string("'{a}'"),
+        comment("# it's"),
+        string("\"\"\"doc \"quoted\"\n\"\"\""),
+        string("'open"),
+    ]);
+
+    let json = "{\"key\": \"a \\\"b\\\"\", // note\n \"n\": 1}";
+    assert_eq!(text(tokens(json.as_bytes(), Language::Json), json), vec![
+        string("\"key\""),
+        string("\"a \\\"b\\\"\""),
+        comment("// note"),
+        string("\"n\""),
+    ]);
+}
+
+#[test]
+fn test_regions() {
+    let text = b"a(\"s\"\"t\") // c";
+    assert_eq!(regions(text, Language::C, Part::Code), vec![(0, 2), (8, 10)]);
+    assert_eq!(regions(text, Language::C, Part::Strings), vec![(2, 5), (5, 8)]);
+    assert_eq!(regions(text, Language::C, Part::Comments), vec![(10, 14)]);
+    assert_eq!(regions(b"", Language::C, Part::Code), vec![]);
+
+    assert_eq!(Language::for_path(Path::new("src/Main.JAVA")), Some(Language::C));
+    assert_eq!(Language::for_path(Path::new("README")), None);
+    assert!(Language::from_name("cobol").is_err() && Part::from_name("docs").is_err());
+}
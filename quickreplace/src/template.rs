@@ -0,0 +1,389 @@
+/*
+Replacement templates
+
+Unless --fixed-strings is given, the replacement is a template. Besides the
+'$1', '${1}', '$name' and '${name}' capture group references that
+'Regex::replace_all' understands, and '$$' for a dollar sign, it may contain:
+
+    \U       upper-case everything up to the next \E, or to the end
+    \L       lower-case everything up to the next \E, or to the end
+    \u       upper-case the next character
+    \l       lower-case the next character
+    \E       end a \U or \L
+    $#       the number of this match in the file, counting from 1
+    $(EXPR)  integer arithmetic with + - * / % and parentheses over numbers,
+             captures ($1, $name, ${name}) and $#
+
+so 'v$($1 + 1)' bumps a captured version number, '\u$1' capitalizes a word
+and 'item-$($# * 10)' numbers items 10, 20, 30... Any other backslash is
+kept as it is, even before another, as when the replacement went straight
+to 'Regex::replace_all': 'C:\\dir' stays 'C:\\dir', and '\n' isn't a line
+break. A capture used in an expression must hold an integer, or the file is
+left alone and reported.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Group(Group),
+    Counter,
+    Expr(Expr),
+    Case(Case),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Group {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Group(Group),
+    Counter,
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Upper,
+    Lower,
+    UpperNext,
+    LowerNext,
+    End,
+}
+
+impl Template {
+    // With 'literal' (--fixed-strings) the replacement is used as it is.
+    pub fn parse(replacement: &str, literal: bool) -> Result<Template, String> {
+        if literal {
+            return Ok(Template { pieces: vec![Piece::Text(replacement.to_string())] });
+        }
+
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut rest = replacement;
+        while let Some(c) = rest.chars().next() {
+            let piece = match c {
+                '\\' => match rest[1..].chars().next() {
+                    Some('U') => Some(Piece::Case(Case::Upper)),
+                    Some('L') => Some(Piece::Case(Case::Lower)),
+                    Some('u') => Some(Piece::Case(Case::UpperNext)),
+                    Some('l') => Some(Piece::Case(Case::LowerNext)),
+                    Some('E') => Some(Piece::Case(Case::End)),
+                    _ => None,
+                },
+                '$' => match rest[1..].chars().next() {
+                    Some('$') => {
+                        text.push('$');
+                        rest = &rest[2..];
+                        continue;
+                    }
+                    Some('#') => Some(Piece::Counter),
+                    Some('(') => {
+                        let end = closing_paren(&rest[1..])
+                            .ok_or_else(|| format!("unclosed '$(' in replacement '{}'", replacement))?;
+                        let expr = parse_expr(&rest[2..end + 1])
+                            .map_err(|e| format!("in '{}': {}", &rest[..end + 2], e))?;
+                        pieces.extend(flush(&mut text));
+                        pieces.push(Piece::Expr(expr));
+                        rest = &rest[end + 2..];
+                        continue;
+                    }
+                    _ => match parse_group(rest) {
+                        Some((group, len)) => {
+                            pieces.extend(flush(&mut text));
+                            pieces.push(Piece::Group(group));
+                            rest = &rest[len..];
+                            continue;
+                        }
+                        None => None,
+                    },
+                },
+                _ => None,
+            };
+            match piece {
+                // All of these are two characters long.
+                Some(piece) => {
+                    pieces.extend(flush(&mut text));
+                    pieces.push(piece);
+                    rest = &rest[2..];
+                }
+                None => {
+                    text.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        pieces.extend(flush(&mut text));
+        Ok(Template { pieces })
+    }
+
+    /*
+    Append the replacement for one match to 'out'. 'group' looks up a capture
+    group of the match, and 'number' is what '$#' stands for.
+     */
+    pub fn expand<'t>(&self, group: impl Fn(&Group) -> Option<&'t [u8]>, number: usize,
+                      out: &mut Vec<u8>) -> Result<(), String> {
+        let mut case = CaseState::default();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => case.push(out, text.as_bytes()),
+                Piece::Group(g) => case.push(out, group(g).unwrap_or_default()),
+                Piece::Counter => case.push(out, number.to_string().as_bytes()),
+                Piece::Expr(expr) => case.push(out, evaluate(expr, &group, number)?.to_string().as_bytes()),
+                Piece::Case(Case::UpperNext) => case.next = Some(true),
+                Piece::Case(Case::LowerNext) => case.next = Some(false),
+                Piece::Case(Case::Upper) => case.upper = Some(true),
+                Piece::Case(Case::Lower) => case.upper = Some(false),
+                Piece::Case(Case::End) => case.upper = None,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn flush(text: &mut String) -> Option<Piece> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(Piece::Text(std::mem::take(text)))
+    }
+}
+
+/*
+A group reference at the start of 'text', which starts with '$', read the way
+the regex crate reads them: '${name}', or else the longest run of letters,
+digits and underscores. Returns the group and the length of the reference.
+ */
+fn parse_group(text: &str) -> Option<(Group, usize)> {
+    let (name, len) = if let Some(braced) = text.strip_prefix("${") {
+        let end = braced.find('}')?;
+        (&braced[..end], end + 3)
+    } else {
+        let name_len = text[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len() - 1);
+        (&text[1..1 + name_len], name_len + 1)
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let group = match name.parse() {
+        Ok(index) => Group::Index(index),
+        Err(_) => Group::Name(name.to_string()),
+    };
+    Some((group, len))
+}
+
+// The index of the ')' closing the '(' that 'text' starts with.
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 1 => return Some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// \U, \L, \u and \l in effect while expanding.
+#[derive(Default)]
+struct CaseState {
+    // Some(true) for \U, Some(false) for \L.
+    upper: Option<bool>,
+    // The same for \u and \l, which only touch the next character.
+    next: Option<bool>,
+}
+
+impl CaseState {
+    fn push(&mut self, out: &mut Vec<u8>, text: &[u8]) {
+        if text.is_empty() {
+            return;
+        }
+        if self.upper.is_none() && self.next.is_none() {
+            out.extend_from_slice(text);
+            return;
+        }
+        match std::str::from_utf8(text) {
+            Ok(text) => {
+                for c in text.chars() {
+                    let mapped: String = match self.next.take().or(self.upper) {
+                        Some(true) => c.to_uppercase().collect(),
+                        Some(false) => c.to_lowercase().collect(),
+                        None => c.to_string(),
+                    };
+                    out.extend_from_slice(mapped.as_bytes());
+                }
+            }
+            // Raw bytes from --bytes: only ASCII letters change case.
+            Err(_) => {
+                for &b in text {
+                    out.push(match self.next.take().or(self.upper) {
+                        Some(true) => b.to_ascii_uppercase(),
+                        Some(false) => b.to_ascii_lowercase(),
+                        None => b,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/*
+Parse the inside of '$(...)':
+
+    expr   = term (('+' | '-') term)*
+    term   = factor (('*' | '/' | '%') factor)*
+    factor = '-' factor | '(' expr ')' | NUMBER | '$#' | '$' NAME | '${' NAME '}'
+ */
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser { text, at: 0 };
+    let expr = parser.expr()?;
+    parser.skip_spaces();
+    if parser.at < text.len() {
+        return Err(format!("unexpected '{}'", &text[parser.at..]));
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        let rest = &self.text[self.at..];
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.text[self.at..].chars().next()
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.at += 1;
+            left = Expr::Binary(Box::new(left), op, Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.factor()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.at += 1;
+            left = Expr::Binary(Box::new(left), op, Box::new(self.factor()?));
+        }
+        Ok(left)
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        let next = self.peek();
+        let rest = &self.text[self.at..];
+        match next {
+            Some('-') => {
+                self.at += 1;
+                Ok(Expr::Negate(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.at += 1;
+                let expr = self.expr()?;
+                if self.peek() != Some(')') {
+                    return Err("missing ')'".to_string());
+                }
+                self.at += 1;
+                Ok(expr)
+            }
+            Some('$') if rest.starts_with("$#") => {
+                self.at += 2;
+                Ok(Expr::Counter)
+            }
+            Some('$') => {
+                let (group, len) = parse_group(rest)
+                    .ok_or_else(|| format!("expected a capture group at '{}'", rest))?;
+                self.at += len;
+                Ok(Expr::Group(group))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                self.at += len;
+                rest[..len].parse().map(Expr::Number).map_err(|e| format!("bad number '{}': {}", &rest[..len], e))
+            }
+            Some(_) => Err(format!("unexpected '{}'", rest)),
+            None => Err("expression ends too soon".to_string()),
+        }
+    }
+}
+
+fn evaluate<'t>(expr: &Expr, group: &impl Fn(&Group) -> Option<&'t [u8]>, number: usize) -> Result<i64, String> {
+    Ok(match expr {
+        Expr::Number(n) => *n,
+        Expr::Counter => number as i64,
+        Expr::Group(g) => {
+            let value = String::from_utf8_lossy(group(g).unwrap_or_default()).into_owned();
+            value.trim().parse()
+                .map_err(|_| format!("capture {} is '{}', not a number", describe(g), value))?
+        }
+        Expr::Negate(e) => evaluate(e, group, number)?.checked_neg().ok_or("arithmetic overflow")?,
+        Expr::Binary(left, op, right) => {
+            let (a, b) = (evaluate(left, group, number)?, evaluate(right, group, number)?);
+            let result = match op {
+                '+' => a.checked_add(b),
+                '-' => a.checked_sub(b),
+                '*' => a.checked_mul(b),
+                '/' if b == 0 => return Err("division by zero".to_string()),
+                '/' => a.checked_div(b),
+                '%' if b == 0 => return Err("division by zero".to_string()),
+                _ => a.checked_rem(b),
+            };
+            result.ok_or("arithmetic overflow")?
+        }
+    })
+}
+
+fn describe(group: &Group) -> String {
+    match group {
+        Group::Index(index) => format!("${}", index),
+        Group::Name(name) => format!("${{{}}}", name),
+    }
+}
+
+#[test]
+fn test_template() {
+    let expand = |template: &str, groups: &[&str], number| {
+        let template = Template::parse(template, false).unwrap();
+        let mut out = Vec::new();
+        let lookup = |g: &Group| match g {
+            Group::Index(i) => groups.get(*i).map(|s| s.as_bytes()),
+            Group::Name(name) if name == "word" => Some(groups[1].as_bytes()),
+            Group::Name(_) => None,
+        };
+        template.expand(lookup, number, &mut out).map(|()| String::from_utf8(out).unwrap())
+    };
+
+    assert_eq!(expand("<$1|${1}x|$1x|$$1|$word>", &["all", "a1"], 1), Ok("<a1|a1x||$1|a1>".to_string()));
+    assert_eq!(expand(r"\U$1\E-\L$2\E-\u$2-\l$2-\uñu", &["", "ab", "CD"], 1), Ok("AB-cd-CD-cD-Ñu".to_string()));
+    // Other backslashes are left alone, doubled ones too.
+    assert_eq!(expand(r"C:\\dir\$1 \n \\n \x", &["", "a"], 1), Ok(r"C:\\dir\a \n \\n \x".to_string()));
+    assert_eq!(expand(r"\\U$1", &["", "a"], 1), Ok(r"\A".to_string()));
+    assert_eq!(expand(r"\u\L$1", &["", "hELLO"], 1), Ok("Hello".to_string()));
+    assert_eq!(expand("item-$# v$($1 + 1) $(($# - 1) * -10 % 7)", &["", "41"], 3), Ok("item-3 v42 -6".to_string()));
+    assert_eq!(expand("$(${word} * 2)", &["", "21"], 1), Ok("42".to_string()));
+    assert!(expand("$($1 + 1)", &["", "x"], 1).unwrap_err().contains("not a number"));
+    assert!(expand("$(1 / ($# - 1))", &[""], 1).is_err());
+
+    assert!(Template::parse("$(1 +", false).is_err());
+    assert!(Template::parse("$(1 + x)", false).is_err());
+    assert_eq!(Template::parse(r"\U$1", true).unwrap(), Template { pieces: vec![Piece::Text(r"\U$1".to_string())] });
+}
@@ -0,0 +1,83 @@
+/*
+Tracing matches back to the original text
+
+With several rules, each rule is matched against the text the rules before
+it left, so where it found a match isn't where that text was to begin with.
+The --json report gives offsets in the file as it was, so each match is
+traced back through the replacements the earlier rules made: text they left
+alone has only moved, by however much the replacements before it grew or
+shrank. An end that falls inside text an earlier rule put in is taken back
+to that end of what the rule replaced, as that's where the text came from.
+ */
+#[derive(Debug, Default)]
+pub struct Trail {
+    // For each rule so far, each replacement it made: where it was in the
+    // rule's input, and where the new text went in its output.
+    rules: Vec<Vec<Step>>,
+}
+
+#[derive(Debug)]
+struct Step {
+    start: usize,
+    end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+impl Trail {
+    // Note the 'replacements' a rule made, in order, in the text it was given.
+    pub fn push(&mut self, replacements: &[(usize, usize, usize)]) {
+        let mut growth = 0;
+        let steps = replacements.iter().map(|&(start, end, len)| {
+            let new_start = (start as isize + growth) as usize;
+            growth += len as isize - (end - start) as isize;
+            Step { start, end, new_start, new_end: new_start + len }
+        });
+        self.rules.push(steps.collect());
+    }
+
+    // Where the range 'start'..'end' in the text the next rule will be given was in the original.
+    pub fn back(&self, mut start: usize, mut end: usize) -> (usize, usize) {
+        for steps in self.rules.iter().rev() {
+            // The first replacement that ends after 'start', or for 'end' at or after it.
+            let i = steps.partition_point(|step| step.new_end <= start);
+            start = match steps.get(i) {
+                Some(step) if step.new_start <= start => step.start,
+                Some(step) => step.start - (step.new_start - start),
+                None => moved(steps, start),
+            };
+            let i = steps.partition_point(|step| step.new_end < end);
+            end = match steps.get(i) {
+                Some(step) if step.new_start < end => step.end,
+                Some(step) => step.start - (step.new_start - end),
+                None => moved(steps, end),
+            };
+        }
+        (start, end)
+    }
+}
+
+// Where 'at', after all the replacements in 'steps', was before them.
+fn moved(steps: &[Step], at: usize) -> usize {
+    steps.last().map_or(at, |step| at - step.new_end + step.end)
+}
+
+#[test]
+fn test_trail() {
+    // "one two three" -> "1 two 3" -> "1 2 3"
+    let mut trail = Trail::default();
+    assert_eq!(trail.back(4, 7), (4, 7));
+    trail.push(&[(0, 3, 1), (8, 13, 1)]);
+    assert_eq!(trail.back(2, 5), (4, 7));
+    trail.push(&[(2, 5, 1)]);
+    assert_eq!(trail.back(4, 5), (8, 13));
+    // Across text the first rule put in, and the second.
+    assert_eq!(trail.back(0, 3), (0, 7));
+    assert_eq!(trail.back(1, 2), (3, 4));
+
+    // Deleted text: a range that ends where it was stops short of it, one that starts there starts after it.
+    let mut trail = Trail::default();
+    trail.push(&[(1, 3, 0)]);
+    assert_eq!(trail.back(0, 1), (0, 1));
+    assert_eq!(trail.back(1, 2), (3, 4));
+}
@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crossbeam::channel;
+use notify::{EventKind, RecursiveMode, Watcher as _};
+
+use crate::journal;
+
+/*
+Watch mode
+
+--watch replaces once as usual and then keeps going, replacing again in
+each file that's created or modified until it's interrupted. Editors and
+build tools tend to touch a file several times in a row when saving it, so
+changes are gathered until things have been quiet for a moment and then
+dealt with together, each file once.
+
+A file is only watched through its directory, since rewriting it in place
+puts a new file in its place. --recursive watches the directories it walks
+and everything under them, so new files are picked up as the walk would
+pick them; --in-place watches the directories of the files named or
+matched by globs.
+
+Rewriting a file changes it, which would set off another round, and with a
+replacement like 's/a/aa/' another and another. So what each round wrote is
+remembered, backups included, and a file that still holds what we left in
+it is passed over: only changes someone else made count.
+ */
+pub struct Watcher {
+    // Dropping the watcher would stop the events.
+    _watcher: notify::RecommendedWatcher,
+    events: channel::Receiver<notify::Result<notify::Event>>,
+}
+
+// How long things must stay quiet before the changes so far are dealt with.
+const SETTLE: Duration = Duration::from_millis(200);
+
+impl Watcher {
+    // Watch each of 'directories', and everything under it if it's marked recursive.
+    pub fn new(directories: &[(PathBuf, bool)]) -> Result<Watcher, String> {
+        let (sender, events) = channel::unbounded();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        }).map_err(|e| format!("failed to start watching for changes: {}", e))?;
+        for (directory, recursive) in directories {
+            let mode = if *recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+            watcher.watch(directory, mode)
+                .map_err(|e| format!("failed to watch '{}': {}", directory.display(), e))?;
+        }
+        Ok(Watcher { _watcher: watcher, events })
+    }
+
+    /*
+    Wait for a file to be created or modified, then until there's been no
+    change for a moment. Returns every file that changed meanwhile and still
+    exists, by its canonical path, and a message for each error the watcher
+    ran into.
+     */
+    pub fn changes(&self) -> (HashSet<PathBuf>, Vec<String>) {
+        settle(&self.events, SETTLE)
+    }
+}
+
+// The changes in 'events' until there's been none for 'quiet'; see 'Watcher::changes'.
+fn settle(events: &channel::Receiver<notify::Result<notify::Event>>,
+          quiet: Duration) -> (HashSet<PathBuf>, Vec<String>) {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+    let mut next = events.recv().ok();
+    while let Some(event) = next {
+        match event {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                paths.extend(event.paths);
+            }
+            Ok(_) => {} // reads and removals
+            Err(e) => errors.push(format!("failed to watch for changes: {}", e)),
+        }
+        next = events.recv_timeout(quiet).ok();
+    }
+    // Only now, since a file may be replaced by another or gone by the time things settle.
+    let changed = paths.iter().filter_map(|path| fs::canonicalize(path).ok()).filter(|path| path.is_file());
+    (changed.collect(), errors)
+}
+
+/*
+The files the inputs came to, by their canonical paths, each with the path
+it goes by in messages. A file that changes is looked up here rather than
+walking the inputs again every round; only a new one means going through
+them once more.
+ */
+#[derive(Debug, Default)]
+pub struct Inputs(HashMap<PathBuf, PathBuf>);
+
+impl Inputs {
+    pub fn add(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            if let Ok(canonical) = fs::canonicalize(path) {
+                self.0.entry(canonical).or_insert_with(|| path.clone());
+            }
+        }
+    }
+
+    pub fn knows(&self, path: &Path) -> bool {
+        self.0.contains_key(path)
+    }
+
+    // What the 'changed' files that are inputs go by, in order.
+    pub fn named(&self, changed: &HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = changed.iter().filter_map(|path| self.0.get(path).cloned()).collect();
+        paths.sort();
+        paths
+    }
+}
+
+// The files we wrote, each with a hash of what we left in it.
+#[derive(Debug, Default)]
+pub struct Written(HashMap<PathBuf, u64>);
+
+impl Written {
+    pub fn note(&mut self, path: &Path, hash: u64) {
+        if let Ok(path) = fs::canonicalize(path) {
+            self.0.insert(path, hash);
+        }
+    }
+
+    // Whether the file at the canonical 'path' holds just what we last wrote there.
+    pub fn is_ours(&self, path: &Path) -> bool {
+        self.0.get(path).is_some_and(|&hash| fs::read(path).is_ok_and(|data| journal::hash(&data) == hash))
+    }
+}
+
+#[test]
+fn test_watch() {
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+
+    let dir = std::env::temp_dir().join(format!("quickreplace-watch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+    for name in ["a.txt", "b.txt"] {
+        fs::write(dir.join(name), name).unwrap();
+    }
+
+    // A burst of events comes as one, without the removals and the files that are gone.
+    let (sender, events) = channel::unbounded();
+    let event = |kind, name: &str| Ok(notify::Event::new(kind).add_path(dir.join(name)));
+    sender.send(event(EventKind::Create(CreateKind::File), "a.txt")).unwrap();
+    sender.send(event(EventKind::Modify(ModifyKind::Any), "a.txt")).unwrap();
+    sender.send(event(EventKind::Modify(ModifyKind::Any), "b.txt")).unwrap();
+    sender.send(event(EventKind::Remove(RemoveKind::File), "c.txt")).unwrap();
+    sender.send(event(EventKind::Create(CreateKind::File), "gone.txt")).unwrap();
+    sender.send(Err(notify::Error::generic("overflow"))).unwrap();
+    drop(sender);
+    let (changed, errors) = settle(&events, Duration::ZERO);
+    assert_eq!(changed, HashSet::from([dir.join("a.txt"), dir.join("b.txt")]));
+    assert_eq!(errors, vec!["failed to watch for changes: overflow"]);
+
+    let mut inputs = Inputs::default();
+    inputs.add(&[dir.join("b.txt"), dir.join(".").join("a.txt"), dir.join("gone.txt")]);
+    assert!(inputs.knows(&dir.join("a.txt")) && !inputs.knows(&dir.join("gone.txt")));
+    assert_eq!(inputs.named(&changed), vec![dir.join(".").join("a.txt"), dir.join("b.txt")]);
+
+    let mut written = Written::default();
+    written.note(&dir.join("a.txt"), journal::hash(b"a.txt"));
+    assert!(written.is_ours(&dir.join("a.txt")) && !written.is_ours(&dir.join("b.txt")));
+    fs::write(dir.join("a.txt"), "abcd").unwrap();
+    assert!(!written.is_ours(&dir.join("a.txt")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}